@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose, Engine as _};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::auth::errors::*;
+use crate::auth::tokens::AccessTokenFormat;
+
+/// A confidential client, authenticating with HTTP Basic `client_id:secret`
+/// (RFC 6749 section 2.3.1).
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClientConfig {
+    pub client_id: String,
+    pub secret: String,
+    /// Overrides `auth.access_token_format` for this client.
+    pub access_token_format: Option<AccessTokenFormat>,
+    /// Resource servers may call `/introspect`.
+    #[serde(default)]
+    pub introspection: bool,
+    /// Audiences this client may exchange tokens for (RFC 8693), out of `auth.audiences`.
+    #[serde(default)]
+    pub exchange_audiences: Vec<String>,
+}
+impl ClientConfig {
+    /// The audiences of a token this client exchanges one issued to `subject_audiences` for:
+    /// those it asked for, or the subject token's own when it asked for none. `None` if it
+    /// asked for any not in its `exchange_audiences`.
+    pub fn resolve_exchange_audiences(
+        &self,
+        requested: Vec<String>,
+        subject_audiences: Vec<String>,
+    ) -> Option<Vec<String>> {
+        if requested.is_empty() {
+            return Some(subject_audiences);
+        }
+        let mut resolved: Vec<String> = Vec::with_capacity(requested.len());
+        for audience in requested {
+            if !self.exchange_audiences.contains(&audience) {
+                return None;
+            }
+            if !resolved.contains(&audience) {
+                resolved.push(audience);
+            }
+        }
+        Some(resolved)
+    }
+}
+
+/// The registered clients, by `client_id`.
+pub struct Clients {
+    clients: HashMap<String, ClientConfig>,
+}
+impl Clients {
+    pub fn new(configs: &[ClientConfig]) -> Clients {
+        Clients {
+            clients: configs
+                .iter()
+                .map(|config| (config.client_id.clone(), config.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn is_registered(&self, client_id: &str) -> bool {
+        self.clients.contains_key(client_id)
+    }
+
+    /// The client the `Authorization` header authenticates, `None` without one. Anything but
+    /// valid Basic credentials of a registered client is an error.
+    pub fn authenticate(&self, authorization: Option<&str>) -> Result<Option<&ClientConfig>> {
+        let Some(authorization) = authorization else {
+            return Ok(None);
+        };
+        let invalid = |message: &str| Error {
+            message: message.to_string(),
+        };
+        let credentials = match authorization.split_once(' ') {
+            Some((scheme, credentials)) if scheme.eq_ignore_ascii_case("Basic") => credentials,
+            _ => return Err(invalid("not Basic authentication")),
+        };
+        let credentials = general_purpose::STANDARD
+            .decode(credentials.trim())
+            .map_err(|_| invalid("malformed Basic credentials"))?;
+        let credentials = String::from_utf8(credentials)?;
+        let (client_id, secret) = credentials
+            .split_once(':')
+            .ok_or_else(|| invalid("malformed Basic credentials"))?;
+        let client = self
+            .clients
+            .get(client_id)
+            .ok_or_else(|| invalid("unknown client"))?;
+        // comparing digests keeps the time taken independent of where the secrets differ
+        if Sha256::digest(secret.as_bytes()) != Sha256::digest(client.secret.as_bytes()) {
+            return Err(invalid("wrong client secret"));
+        }
+        Ok(Some(client))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gateway() -> ClientConfig {
+        ClientConfig {
+            client_id: "api-gateway".to_string(),
+            secret: "gateway-secret-0123".to_string(),
+            access_token_format: None,
+            introspection: false,
+            exchange_audiences: vec!["orders".to_string(), "billing".to_string()],
+        }
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", general_purpose::STANDARD.encode(credentials))
+    }
+
+    #[test]
+    fn authenticate_checks_the_secret() {
+        let clients = Clients::new(&[gateway()]);
+        let authenticated = clients
+            .authenticate(Some(&basic("api-gateway:gateway-secret-0123")))
+            .unwrap();
+        assert_eq!(authenticated.unwrap().client_id, "api-gateway");
+        assert!(clients.authenticate(None).unwrap().is_none());
+        assert!(clients
+            .authenticate(Some(&basic("api-gateway:gateway-secret-012")))
+            .is_err());
+        assert!(clients
+            .authenticate(Some(&basic("someone:gateway-secret-0123")))
+            .is_err());
+        assert!(clients.authenticate(Some("Bearer abc")).is_err());
+    }
+
+    #[test]
+    fn exchange_audiences_are_limited_to_the_allow_list() {
+        let client = gateway();
+        let subject = vec!["simple-auth".to_string()];
+        assert_eq!(
+            client.resolve_exchange_audiences(
+                vec![
+                    "billing".to_string(),
+                    "orders".to_string(),
+                    "billing".to_string()
+                ],
+                subject.clone()
+            ),
+            Some(vec!["billing".to_string(), "orders".to_string()])
+        );
+        assert_eq!(
+            client.resolve_exchange_audiences(
+                vec!["orders".to_string(), "simple-auth".to_string()],
+                subject.clone()
+            ),
+            None
+        );
+        // asking for nothing keeps the subject token's audience rather than the default one
+        assert_eq!(
+            client.resolve_exchange_audiences(Vec::new(), subject.clone()),
+            Some(subject)
+        );
+    }
+}
@@ -1,3 +1,7 @@
 pub mod claims;
+pub mod clients;
+pub mod encryption;
 pub mod errors;
+pub mod keys;
+pub mod mapper;
 pub mod tokens;
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use deadpool_postgres::Pool;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::auth::keys::SigningKeys;
+use crate::blocking::BlockingPool;
+use crate::shutdown::Shutdown;
+
+const DB_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a signing key check is trusted; the keys don't change while we run.
+const SIGNING_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+impl Check {
+    fn from_result<E: ToString>(result: Result<String, E>) -> Self {
+        match result {
+            Ok(detail) => Check {
+                ok: true,
+                detail: Some(detail).filter(|d| !d.is_empty()),
+            },
+            Err(err) => Check {
+                ok: false,
+                detail: Some(err.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub draining: bool,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+/// Signs and verifies a probe token on the signing pool, at most once per
+/// `SIGNING_CHECK_INTERVAL`, so unauthenticated probes stay cheap and off the async workers.
+pub struct SigningKeyCheck {
+    signing_keys: Arc<SigningKeys>,
+    last: Mutex<Option<(Instant, Result<String, String>)>>,
+}
+impl SigningKeyCheck {
+    pub fn new(signing_keys: Arc<SigningKeys>) -> Self {
+        SigningKeyCheck {
+            signing_keys,
+            last: Mutex::new(None),
+        }
+    }
+
+    async fn run(&self, signing_pool: &BlockingPool) -> Result<String, String> {
+        // held across the check so concurrent probes wait for one result
+        let mut last = self.last.lock().await;
+        if let Some((checked_at, ref result)) = *last {
+            if checked_at.elapsed() < SIGNING_CHECK_INTERVAL {
+                return result.clone();
+            }
+        }
+        let signing_keys = self.signing_keys.clone();
+        // a busy pool fails this probe only, the key itself was not checked
+        let checked = signing_pool
+            .run(move || signing_keys.check_pair())
+            .await
+            .map_err(|err| err.to_string())?;
+        let result = checked.map(|_| String::new()).map_err(|err| err.message);
+        *last = Some((Instant::now(), result.clone()));
+        result
+    }
+}
+
+/// Ready when we are not shutting down, Postgres answers through the pool, the pool has
+/// room left and the signing key still signs tokens we can verify.
+pub async fn readiness(
+    pool: &Pool,
+    signing_check: &SigningKeyCheck,
+    signing_pool: &BlockingPool,
+    shutdown: &Shutdown,
+) -> Readiness {
+    let mut checks = BTreeMap::new();
+    checks.insert("postgres", Check::from_result(check_postgres(pool).await));
+    checks.insert("pool", Check::from_result(check_pool(pool)));
+    checks.insert(
+        "signing_key",
+        Check::from_result(signing_check.run(signing_pool).await),
+    );
+
+    let draining = shutdown.is_draining();
+    Readiness {
+        ready: !draining && checks.values().all(|check| check.ok),
+        draining,
+        checks,
+    }
+}
+
+async fn check_postgres(pool: &Pool) -> Result<String, String> {
+    let round_trip = async {
+        let client = pool.get().await.map_err(|err| err.to_string())?;
+        client
+            .simple_query("SELECT 1")
+            .await
+            .map_err(|err| err.to_string())
+    };
+    match tokio::time::timeout(DB_CHECK_TIMEOUT, round_trip).await {
+        Ok(result) => result.map(|_| String::new()),
+        Err(_) => Err(format!("no answer within {}s", DB_CHECK_TIMEOUT.as_secs())),
+    }
+}
+
+fn check_pool(pool: &Pool) -> Result<String, String> {
+    let status = pool.status();
+    let detail = format!(
+        "size {} available {} max {}",
+        status.size, status.available, status.max_size
+    );
+    // available goes negative when requests are queueing for a connection
+    if status.size >= status.max_size && status.available <= 0 {
+        Err(format!("saturated: {}", detail))
+    } else {
+        Ok(detail)
+    }
+}
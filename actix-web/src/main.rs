@@ -1,32 +1,139 @@
+mod audit;
 mod auth;
-
-mod config {
-    use serde::Deserialize;
-    #[derive(Debug, Default, Deserialize)]
-    pub struct SimpleAuthConfig {
-        pub server_addr: String,
-        pub pg: deadpool_postgres::Config,
-    }
-}
+mod blocking;
+mod cache;
+mod config;
+mod health;
+mod logging;
+mod metrics;
+mod shutdown;
+mod telemetry;
+mod tls;
 
 mod models {
-    use crate::auth::claims::IdClaims;
+    use crate::auth::claims::{Audience, IdClaims};
     use serde::{Deserialize, Serialize};
+    use serde_json::Map;
+    use std::collections::HashMap;
+    use std::fmt;
     use tokio_pg_mapper_derive::PostgresMapper;
 
-    #[derive(Debug, Serialize)]
+    #[derive(Serialize)]
     pub struct TokenResponse {
         pub access_token: String,
         pub id_token: String,
     }
+    // tokens and passwords must never end up in the logs
+    impl fmt::Debug for TokenResponse {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("TokenResponse").finish_non_exhaustive()
+        }
+    }
+
+    pub const TOKEN_EXCHANGE_GRANT: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+    pub const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+    /// RFC 8693 token exchange request, form encoded.
+    pub struct TokenExchangeRequest {
+        pub grant_type: String,
+        pub subject_token: String,
+        pub subject_token_type: String,
+        pub actor_token: Option<String>,
+        pub actor_token_type: Option<String>,
+        pub requested_token_type: Option<String>,
+        pub resource: Vec<String>,
+        pub audience: Vec<String>,
+        pub scope: Option<String>,
+    }
+    impl TokenExchangeRequest {
+        /// From the form parameters in order. `resource` and `audience` may be repeated, which
+        /// a plain form struct rejects; `None` if any other one is, or a required one is missing.
+        pub fn from_params(params: Vec<(String, String)>) -> Option<Self> {
+            let mut single: HashMap<String, String> = HashMap::new();
+            let mut resource = Vec::new();
+            let mut audience = Vec::new();
+            for (name, value) in params {
+                match name.as_str() {
+                    "resource" => resource.push(value),
+                    "audience" => audience.push(value),
+                    _ => {
+                        if single.insert(name, value).is_some() {
+                            return None;
+                        }
+                    }
+                }
+            }
+            Some(TokenExchangeRequest {
+                grant_type: single.remove("grant_type")?,
+                subject_token: single.remove("subject_token")?,
+                subject_token_type: single.remove("subject_token_type")?,
+                actor_token: single.remove("actor_token"),
+                actor_token_type: single.remove("actor_token_type"),
+                requested_token_type: single.remove("requested_token_type"),
+                resource,
+                audience,
+                scope: single.remove("scope"),
+            })
+        }
+    }
+
+    #[derive(Serialize)]
+    pub struct TokenExchangeResponse {
+        pub access_token: String,
+        pub issued_token_type: &'static str,
+        pub token_type: &'static str,
+        pub expires_in: u64,
+        pub scope: String,
+    }
 
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Serialize, Deserialize)]
     pub struct LogonRequest {
         pub username: String,
         pub password: String,
+        /// RFC 8707 resource indicators; each must be one of `auth.audiences`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub resource: Option<Audience>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub audience: Option<Audience>,
+        /// Space separated; defaults to every scope in `auth.scopes`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub scope: Option<String>,
+        /// Echoed in the ID token, as OIDC relying parties require.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub nonce: Option<String>,
+        /// Becomes the ID token's `azp`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub client_id: Option<String>,
+    }
+    impl LogonRequest {
+        /// Everything asked for through either `resource` or `audience`.
+        pub fn requested_audiences(&self) -> Vec<String> {
+            [&self.resource, &self.audience]
+                .into_iter()
+                .flatten()
+                .flat_map(|audience| audience.clone().into_vec())
+                .collect()
+        }
+    }
+    /// RFC 7662 introspection request.
+    #[derive(Deserialize)]
+    pub struct IntrospectionRequest {
+        pub token: String,
     }
 
-    #[derive(Deserialize, PostgresMapper, Serialize)]
+    impl fmt::Debug for LogonRequest {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("LogonRequest")
+                .field("username", &self.username)
+                .field("resource", &self.resource)
+                .field("audience", &self.audience)
+                .field("scope", &self.scope)
+                .field("client_id", &self.client_id)
+                .finish_non_exhaustive()
+        }
+    }
+
+    #[derive(Clone, Deserialize, PostgresMapper, Serialize)]
     #[pg_mapper(table = "users")] // singular 'user' is a keyword..
     pub struct User {
         pub id: String,
@@ -36,37 +143,136 @@ mod models {
         pub salt: String,
     }
     impl User {
-        pub fn to_id_claims(self) -> IdClaims {
+        pub fn to_id_claims(&self) -> IdClaims {
             IdClaims {
-                name: self.name,
-                email: self.email,
-                id: self.id,
+                name: self.name.to_string(),
+                email: self.email.to_string(),
+                id: self.id.to_string(),
                 at_hash: None,
+                c_hash: None,
+                nonce: None,
+                auth_time: None,
+                azp: None,
+                acr: None,
+                extra: Map::new(),
             }
         }
+
+        /// `user_attributes` entries plus the `users` columns, which take precedence.
+        pub fn attributes(
+            &self,
+            mut attributes: HashMap<String, Vec<String>>,
+        ) -> HashMap<String, Vec<String>> {
+            attributes.insert("id".to_string(), vec![self.id.clone()]);
+            attributes.insert("name".to_string(), vec![self.name.clone()]);
+            attributes.insert("email".to_string(), vec![self.email.clone()]);
+            attributes
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|&(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        }
+
+        #[test]
+        fn token_exchange_request_takes_repeated_audiences() {
+            let request = TokenExchangeRequest::from_params(params(&[
+                ("grant_type", TOKEN_EXCHANGE_GRANT),
+                ("subject_token", "token"),
+                ("subject_token_type", ACCESS_TOKEN_TYPE),
+                ("audience", "orders"),
+                ("resource", "https://billing.example.com"),
+                ("audience", "shipping"),
+            ]))
+            .unwrap();
+            assert_eq!(request.audience, ["orders", "shipping"]);
+            assert_eq!(request.resource, ["https://billing.example.com"]);
+            assert!(request.actor_token.is_none());
+        }
+
+        #[test]
+        fn token_exchange_request_rejects_repeated_or_missing_parameters() {
+            let repeated = params(&[
+                ("grant_type", TOKEN_EXCHANGE_GRANT),
+                ("subject_token", "token"),
+                ("subject_token", "other"),
+                ("subject_token_type", ACCESS_TOKEN_TYPE),
+            ]);
+            assert!(TokenExchangeRequest::from_params(repeated).is_none());
+            let missing = params(&[("grant_type", TOKEN_EXCHANGE_GRANT)]);
+            assert!(TokenExchangeRequest::from_params(missing).is_none());
+        }
     }
 }
 
 mod errors {
+    use crate::auth::errors::Error as AuthError;
+    use actix_web::http::header;
     use actix_web::{HttpResponse, ResponseError};
     use deadpool_postgres::PoolError;
     use derive_more::{Display, From};
+    use serde_json::json;
+    use tokio::task::JoinError;
     use tokio_pg_mapper::Error as PGMError;
     use tokio_postgres::error::Error as PGError;
 
     #[derive(Display, From, Debug)]
     pub enum MyError {
         NotFound,
+        IncorrectPassword,
+        InvalidRequest,
+        InvalidGrant,
+        InvalidScope,
+        InvalidTarget,
+        UnsupportedGrantType,
+        InvalidClient,
+        InvalidToken,
+        InsufficientScope,
+        Overloaded,
         PGError(PGError),
         PGMError(PGMError),
         PoolError(PoolError),
+        JoinError(JoinError),
+        AuthError(AuthError),
     }
     impl std::error::Error for MyError {}
 
+    fn oauth_error(error: &str) -> HttpResponse {
+        HttpResponse::BadRequest().json(json!({ "error": error }))
+    }
+
     impl ResponseError for MyError {
         fn error_response(&self) -> HttpResponse {
             match *self {
                 MyError::NotFound => HttpResponse::NotFound().finish(),
+                MyError::IncorrectPassword => {
+                    HttpResponse::InternalServerError().body("Incorrect password")
+                }
+                MyError::InvalidRequest => oauth_error("invalid_request"),
+                MyError::InvalidGrant => oauth_error("invalid_grant"),
+                MyError::InvalidScope => oauth_error("invalid_scope"),
+                MyError::InvalidTarget => oauth_error("invalid_target"),
+                MyError::UnsupportedGrantType => oauth_error("unsupported_grant_type"),
+                MyError::InvalidClient => HttpResponse::Unauthorized()
+                    .insert_header((header::WWW_AUTHENTICATE, r#"Basic realm="simple-auth""#))
+                    .json(json!({ "error": "invalid_client" })),
+                MyError::InvalidToken => HttpResponse::Unauthorized()
+                    .insert_header((header::WWW_AUTHENTICATE, r#"Bearer error="invalid_token""#))
+                    .finish(),
+                MyError::InsufficientScope => HttpResponse::Forbidden()
+                    .insert_header((
+                        header::WWW_AUTHENTICATE,
+                        r#"Bearer error="insufficient_scope", scope="openid""#,
+                    ))
+                    .finish(),
+                MyError::Overloaded => HttpResponse::ServiceUnavailable().finish(),
                 MyError::PoolError(ref err) => {
                     HttpResponse::InternalServerError().body(err.to_string())
                 }
@@ -77,45 +283,178 @@ mod errors {
 }
 
 mod db {
-    use deadpool_postgres::Client;
+    use deadpool_postgres::{Client, Pool};
+    use std::collections::HashMap;
+    use std::time::Duration;
     use tokio_pg_mapper::FromTokioPostgresRow;
+    use tracing::{info, warn};
 
     use crate::{
+        cache::UserCache,
         errors::MyError,
         models::{LogonRequest, User},
     };
 
+    const SELECT_USER_BY_EMAIL: &str =
+        "SELECT id::TEXT, name, email, hashpassword, salt from users where email = $1;";
+    const SELECT_USER_BY_ID: &str =
+        "SELECT id::TEXT, name, email, hashpassword, salt from users where id = $1::TEXT::uuid;";
+    const INSERT_ACCESS_TOKEN: &str = "INSERT INTO access_tokens \
+         (token_hash, user_id, client_id, claims, expires_at) \
+         VALUES ($1, $2::TEXT::uuid, $3, $4::TEXT::jsonb, to_timestamp($5::BIGINT));";
+    const SELECT_ACCESS_TOKEN: &str = "SELECT claims::TEXT FROM access_tokens \
+         WHERE token_hash = $1 AND expires_at > now();";
+    const DELETE_EXPIRED_ACCESS_TOKENS: &str =
+        "DELETE FROM access_tokens WHERE expires_at <= now();";
+    const SELECT_USER_ATTRIBUTES: &str = "SELECT name, value FROM user_attributes \
+         WHERE user_id = $1::TEXT::uuid ORDER BY name, value;";
+
     pub async fn get_user(client: &Client, user_info: &LogonRequest) -> Result<User, MyError> {
-        let _stmt = "SELECT id::TEXT, name, email, hashpassword, salt from users where email = $1;"
-            .to_string();
-        let stmt = client.prepare(&_stmt).await.unwrap();
+        // prepared once per pooled connection, then served from deadpool's statement cache
+        let stmt = client.prepare_cached(SELECT_USER_BY_EMAIL).await?;
 
         client
             .query(&stmt, &[&user_info.username])
             .await?
             .iter()
-            .map(|row| User::from_row_ref(row).unwrap())
-            .collect::<Vec<User>>()
+            .map(User::from_row_ref)
+            .collect::<Result<Vec<User>, _>>()?
             .pop()
             .ok_or(MyError::NotFound) // more applicable for SELECTs
     }
+
+    pub async fn get_user_by_id(pool: &Pool, user_id: &str) -> Result<User, MyError> {
+        let client: Client = pool.get().await?;
+        let stmt = client.prepare_cached(SELECT_USER_BY_ID).await?;
+        client
+            .query_opt(&stmt, &[&user_id])
+            .await?
+            .map(User::from_row)
+            .transpose()?
+            .ok_or(MyError::NotFound)
+    }
+
+    /// Only the hash of an opaque token is stored, next to the claims it stands for.
+    pub async fn store_access_token(
+        pool: &Pool,
+        token_hash: &str,
+        user_id: &str,
+        client_id: Option<&str>,
+        claims: &str,
+        expires_at: i64,
+    ) -> Result<(), MyError> {
+        let client: Client = pool.get().await?;
+        let stmt = client.prepare_cached(INSERT_ACCESS_TOKEN).await?;
+        client
+            .execute(
+                &stmt,
+                &[&token_hash, &user_id, &client_id, &claims, &expires_at],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// The claims of an unexpired opaque token, as JSON.
+    pub async fn find_access_token(
+        pool: &Pool,
+        token_hash: &str,
+    ) -> Result<Option<String>, MyError> {
+        let client: Client = pool.get().await?;
+        let stmt = client.prepare_cached(SELECT_ACCESS_TOKEN).await?;
+        let row = client.query_opt(&stmt, &[&token_hash]).await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    pub async fn delete_expired_access_tokens(pool: &Pool) -> Result<u64, MyError> {
+        let client: Client = pool.get().await?;
+        let stmt = client.prepare_cached(DELETE_EXPIRED_ACCESS_TOKENS).await?;
+        Ok(client.execute(&stmt, &[]).await?)
+    }
+
+    /// Expired opaque tokens no longer resolve, so their rows are deleted every `interval`.
+    pub async fn purge_expired_access_tokens(pool: Pool, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            match delete_expired_access_tokens(&pool).await {
+                Ok(0) => {}
+                Ok(deleted) => info!(deleted, "Purged expired opaque access tokens"),
+                Err(err) => warn!(error = %err, "Purging expired opaque access tokens failed"),
+            }
+        }
+    }
+
+    /// Multi-valued attributes (e.g. groups) have one row per value.
+    pub async fn get_user_attributes(
+        pool: &Pool,
+        user_id: &str,
+    ) -> Result<HashMap<String, Vec<String>>, MyError> {
+        let client: Client = pool.get().await?;
+        let stmt = client.prepare_cached(SELECT_USER_ATTRIBUTES).await?;
+        let mut attributes: HashMap<String, Vec<String>> = HashMap::new();
+        for row in client.query(&stmt, &[&user_id]).await? {
+            attributes
+                .entry(row.try_get("name")?)
+                .or_default()
+                .push(row.try_get("value")?);
+        }
+        Ok(attributes)
+    }
+
+    /// Looks the user up in the cache first, only checking out a connection on a miss.
+    pub async fn find_user(
+        pool: &Pool,
+        cache: Option<&UserCache>,
+        user_info: &LogonRequest,
+    ) -> Result<User, MyError> {
+        if let Some(cached) = cache.and_then(|c| c.get(&user_info.username)) {
+            return cached.ok_or(MyError::NotFound);
+        }
+
+        // taken before the read, so a change committed while it runs keeps the row out
+        let generation = cache.map(UserCache::generation);
+        let client: Client = pool.get().await?;
+        let result = get_user(&client, user_info).await;
+        if let (Some(cache), Some(generation)) = (cache, generation) {
+            match result {
+                Ok(ref user) => cache.insert(&user_info.username, Some(user.clone()), generation),
+                Err(MyError::NotFound) => cache.insert(&user_info.username, None, generation),
+                Err(_) => {}
+            }
+        }
+        result
+    }
 }
 
 mod handlers {
-    use crate::auth::claims::{AccessClaims, JwtClaim};
-    use crate::auth::tokens::TokenPair;
+    use crate::audit::AuditEvent;
+    use crate::auth::tokens::{self, AccessToken, AccessTokenFormat, IssueOptions, TokenPair};
+    use crate::auth::{
+        self,
+        claims::{AccessClaims, Actor, DecodedClaims, JwtClaim},
+        keys::JwkSet,
+    };
     use crate::{
         db,
-        models::{LogonRequest, TokenResponse},
+        errors::MyError,
+        health, metrics,
+        models::{
+            IntrospectionRequest, LogonRequest, TokenExchangeRequest, TokenExchangeResponse,
+            TokenResponse, ACCESS_TOKEN_TYPE, TOKEN_EXCHANGE_GRANT,
+        },
+        AppState,
     };
-    use actix_web::{web, Error, HttpResponse};
+    use actix_web::guard::GuardContext;
+    use actix_web::http::header;
+    use actix_web::{web, Error, HttpRequest, HttpResponse};
     use base64::{engine::general_purpose, Engine as _};
-    use chrono::Duration;
-    use deadpool_postgres::{Client, Pool};
     use hmac::{Hmac, Mac};
-    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use serde_json::{json, Map, Value};
     use sha2::Sha256;
+    use std::collections::HashMap;
     use std::time::Instant;
+    use tracing::field::Empty;
+    use tracing::{error, info, info_span, instrument, Instrument, Span};
     use uuid::Uuid;
 
     type HmacSha256 = Hmac<Sha256>;
@@ -128,101 +467,758 @@ mod handlers {
         general_purpose::STANDARD.encode(result)
     }
 
+    #[instrument(
+        name = "logon",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, db_ms = Empty, hash_ms = Empty, token_ms = Empty)
+    )]
     pub async fn logon_user(
+        req: HttpRequest,
         logon_req: web::Json<LogonRequest>,
-        state: web::Data<(Pool, EncodingKey)>,
+        state: web::Data<AppState>,
     ) -> Result<HttpResponse, Error> {
-        let user_info: LogonRequest = logon_req.into_inner();
+        let email = logon_req.username.clone();
+        let result = issue_tokens(&state, authorization(&req), logon_req.into_inner()).await;
+        state.metrics.record_login(&result);
+        let outcome = metrics::login_outcome(&result);
+        Span::current().record("outcome", outcome);
+        match result {
+            // the other outcomes say all there is to know; this one hides the cause
+            Err(ref err) if outcome == "error" => error!(outcome, error = %err, "token request"),
+            _ => info!(outcome, "token request"),
+        }
+        if let (Err(_), Some(audit)) = (&result, &state.audit) {
+            audit.record(AuditEvent::LoginFailed {
+                email,
+                reason: outcome,
+            });
+        }
+        Ok(HttpResponse::Ok().json(result?))
+    }
 
-        // get 'static' variables from the state
-        let (db_pool, encoding_key) = state.get_ref();
+    async fn issue_tokens(
+        state: &AppState,
+        authorization: Option<&str>,
+        user_info: LogonRequest,
+    ) -> Result<TokenResponse, MyError> {
+        let client = state
+            .clients
+            .authenticate(authorization)
+            .map_err(|_| MyError::InvalidClient)?;
+        // a registered client has to authenticate, and can't speak for another one
+        let client_id = match (client, user_info.client_id.clone()) {
+            (Some(client), Some(client_id)) if client_id != client.client_id => {
+                return Err(MyError::InvalidClient)
+            }
+            (Some(client), _) => Some(client.client_id.clone()),
+            (None, Some(client_id)) if state.clients.is_registered(&client_id) => {
+                return Err(MyError::InvalidClient)
+            }
+            (None, client_id) => client_id,
+        };
+        let access_token_format = client
+            .and_then(|client| client.access_token_format)
+            .unwrap_or(state.auth.access_token_format);
+
+        let audiences = state
+            .auth
+            .resolve_audiences(user_info.requested_audiences())
+            .ok_or(MyError::InvalidTarget)?;
 
         let start = Instant::now();
-        let client: Client = db_pool.get().await.unwrap();
-        let user_from_db = db::get_user(&client, &user_info).await?;
+        let user_from_db = db::find_user(&state.pool, state.user_cache.as_deref(), &user_info)
+            .instrument(info_span!("db_lookup"))
+            .await?;
         let time_db = start.elapsed();
+        state.metrics.observe_phase("db", time_db);
+        Span::current().record("db_ms", time_db.as_secs_f64() * 1000.0);
 
-        let encoded = hash_password(&user_info.password, &user_from_db.salt);
-        if encoded != user_from_db.hashpassword.to_string() {
-            Ok(HttpResponse::InternalServerError().body("Incorrect password"))
+        let password = user_info.password;
+        let salt = user_from_db.salt.clone();
+        let encoded = state
+            .signing_pool
+            .run(move || hash_password(&password, &salt))
+            .instrument(info_span!("password_hash"))
+            .await?;
+        let time_hash = start.elapsed() - time_db;
+        state.metrics.observe_phase("hash", time_hash);
+        Span::current().record("hash_ms", time_hash.as_secs_f64() * 1000.0);
+        if encoded != user_from_db.hashpassword {
+            return Err(MyError::IncorrectPassword);
+        }
+        Span::current().record("user_id", user_from_db.id.as_str());
+        let user_id = user_from_db.id.clone();
+
+        let attributes = if state.claims.needs_attributes() {
+            db::get_user_attributes(&state.pool, &user_id)
+                .instrument(info_span!("attribute_lookup"))
+                .await?
         } else {
-            let time_hash = start.elapsed() - time_db;
-
-            let header = Header::new(Algorithm::RS256);
-            let common_claims = JwtClaim::empty()
-                .with_audience("simple-auth.example.com".to_string())
-                .with_issuer("https://example.com".to_string())
-                .issued_now()
-                .expires_in(Duration::minutes(60).num_seconds().unsigned_abs());
-
-            let id_claims = user_from_db.to_id_claims();
-            let access_claims = AccessClaims {
-                session_id: Uuid::new_v4().to_string(),
-            };
+            HashMap::new()
+        };
+        let attributes = user_from_db.attributes(attributes);
 
-            let token_pair = TokenPair::create(
-                &encoding_key,
-                &header,
-                common_claims,
-                id_claims,
-                access_claims,
+        let auth_config = &state.auth;
+        let common_claims = JwtClaim::empty()
+            .with_issuer(auth_config.issuer.clone())
+            .with_subject(user_id.clone())
+            .issued_now()
+            .not_before(0);
+        // each token gets its own jti so either can be revoked or traced on its own
+        let id_token_jti = Uuid::new_v4().to_string();
+        let access_token_jti = Uuid::new_v4().to_string();
+        // the ID token is meant for the client (OIDC Core 2), the requested audiences are the
+        // resource servers the access token is meant for
+        let id_token_audience = client_id
+            .clone()
+            .unwrap_or_else(|| auth_config.audiences[0].clone());
+        let id_jwt_claims = common_claims
+            .clone()
+            .with_audiences(vec![id_token_audience])
+            .with_jwt_id(id_token_jti.clone())
+            .expires_in(auth_config.id_token_lifetime_secs);
+        let access_jwt_claims = common_claims
+            .with_audiences(audiences)
+            .with_jwt_id(access_token_jti.clone())
+            .expires_in(auth_config.access_token_lifetime_secs);
+
+        let mut id_claims = user_from_db
+            .to_id_claims()
+            .authenticated_now()
+            .with_acr(auth_config.password_acr.clone())
+            .with_extra(state.claims.id_token_claims(&attributes));
+        if let Some(nonce) = user_info.nonce {
+            id_claims = id_claims.with_nonce(nonce);
+        }
+        if let Some(ref client_id) = client_id {
+            id_claims = id_claims.with_authorized_party(client_id.clone());
+        }
+        let access_claims = AccessClaims {
+            session_id: Uuid::new_v4().to_string(),
+            scope: state.auth.resolve_scope(user_info.scope.as_deref()),
+            act: None,
+            extra: state.claims.access_token_claims(&attributes),
+        };
+
+        let session_id = access_claims.session_id.clone();
+        let opaque_access_token =
+            (access_token_format == AccessTokenFormat::Opaque).then(tokens::opaque_token);
+        let options = IssueOptions {
+            at_hash_format: auth_config.at_hash_format,
+            authorization_code: None,
+            opaque_access_token: opaque_access_token.clone(),
+        };
+        let signing_keys = state.signing_keys.clone();
+        let id_token_encryption = state.id_token_encryption.clone();
+        let encryption_client_id = client_id.clone();
+        let (token_pair, id_token) = state
+            .signing_pool
+            .run(move || {
+                let token_pair = TokenPair::create(
+                    &signing_keys,
+                    id_jwt_claims,
+                    access_jwt_claims,
+                    id_claims,
+                    access_claims,
+                    options,
+                )?;
+                let id_token = id_token_encryption.encrypt(
+                    encryption_client_id.as_deref(),
+                    token_pair.id_token.raw.clone(),
+                )?;
+                Ok::<_, auth::errors::Error>((token_pair, id_token))
+            })
+            .instrument(info_span!("token_signing"))
+            .await??;
+        let time_token = start.elapsed() - time_hash - time_db;
+        state.metrics.observe_phase("token", time_token);
+        Span::current().record("token_ms", time_token.as_secs_f64() * 1000.0);
+
+        if let Some(ref handle) = opaque_access_token {
+            let access_token = &token_pair.access_token;
+            let claims = json!(DecodedClaims {
+                claims: access_token.claims.clone(),
+                content: access_token.content.clone(),
+            });
+            db::store_access_token(
+                &state.pool,
+                &tokens::opaque_token_hash(handle),
+                &user_id,
+                client_id.as_deref(),
+                &claims.to_string(),
+                access_token.claims.exp.unwrap_or_default() as i64,
             )
-            .unwrap();
-            let time_token = start.elapsed() - time_hash - time_db;
+            .await?;
+        }
+
+        if let Some(audit) = &state.audit {
+            audit.record(AuditEvent::LoginSucceeded {
+                email: user_info.username,
+                user_id: user_id.clone(),
+            });
+            audit.record(AuditEvent::TokenIssued {
+                user_id,
+                session_id,
+                id_token_jti,
+                access_token_jti,
+            });
+        }
+
+        Ok(TokenResponse {
+            access_token: token_pair.access_token.raw,
+            id_token,
+        })
+    }
+
+    #[instrument(name = "token_exchange", skip_all, fields(user_id = Empty, actor = Empty))]
+    pub async fn exchange_token(
+        req: HttpRequest,
+        form: web::Form<Vec<(String, String)>>,
+        state: web::Data<AppState>,
+    ) -> Result<HttpResponse, Error> {
+        let request =
+            TokenExchangeRequest::from_params(form.into_inner()).ok_or(MyError::InvalidRequest)?;
+        let response = exchange(&state, authorization(&req), request).await?;
+        Ok(HttpResponse::Ok().json(response))
+    }
+
+    /// RFC 8693 token exchange: a narrower access token for the same user and session, aimed at
+    /// an audience the authenticated client may ask for and naming the actor.
+    async fn exchange(
+        state: &AppState,
+        authorization: Option<&str>,
+        request: TokenExchangeRequest,
+    ) -> Result<TokenExchangeResponse, MyError> {
+        if request.grant_type != TOKEN_EXCHANGE_GRANT {
+            return Err(MyError::UnsupportedGrantType);
+        }
+        let client = state
+            .clients
+            .authenticate(authorization)
+            .ok()
+            .flatten()
+            .ok_or(MyError::InvalidClient)?;
+        let token_types_supported = request.subject_token_type == ACCESS_TOKEN_TYPE
+            && request
+                .requested_token_type
+                .as_deref()
+                .is_none_or(|token_type| token_type == ACCESS_TOKEN_TYPE);
+        if !token_types_supported {
+            return Err(MyError::InvalidRequest);
+        }
+
+        let subject = resolve_access_token(state, request.subject_token)
+            .await?
+            .ok_or(MyError::InvalidGrant)?;
+        let user_id = subject.claims.sub.ok_or(MyError::InvalidGrant)?;
+        Span::current().record("user_id", user_id.as_str());
+        // the actor is whoever the actor token was issued to, or else the client itself
+        let actor = match request.actor_token {
+            Some(actor_token) => {
+                if request.actor_token_type.as_deref() != Some(ACCESS_TOKEN_TYPE) {
+                    return Err(MyError::InvalidRequest);
+                }
+                resolve_access_token(state, actor_token)
+                    .await?
+                    .and_then(|actor| actor.claims.sub)
+                    .ok_or(MyError::InvalidGrant)?
+            }
+            None => client.client_id.clone(),
+        };
+        Span::current().record("actor", actor.as_str());
 
-            let response = TokenResponse {
-                access_token: token_pair.access_token.raw,
-                id_token: token_pair.id_token.raw,
+        let scope = narrow_scope(&subject.content.scope, request.scope.as_deref())
+            .ok_or(MyError::InvalidScope)?;
+        let requested_audiences = [request.resource, request.audience].concat();
+        let subject_audiences = subject
+            .claims
+            .aud
+            .map(|aud| aud.into_vec())
+            .unwrap_or_default();
+        let audiences = client
+            .resolve_exchange_audiences(requested_audiences, subject_audiences)
+            .ok_or(MyError::InvalidTarget)?;
+
+        // the new token never outlives the one it was exchanged for
+        let now = chrono::Utc::now().timestamp().max(0) as u64;
+        let remaining = subject.claims.exp.unwrap_or_default().saturating_sub(now);
+        let expires_in = state.auth.access_token_lifetime_secs.min(remaining);
+        let jti = Uuid::new_v4().to_string();
+        let jwt_claims = JwtClaim::empty()
+            .with_audiences(audiences)
+            .with_issuer(state.auth.issuer.clone())
+            .with_subject(user_id.clone())
+            .issued_now()
+            .not_before(0)
+            .with_jwt_id(jti.clone())
+            .expires_in(expires_in);
+        let session_id = subject.content.session_id.clone();
+        let content = AccessClaims {
+            session_id: subject.content.session_id,
+            scope: scope.clone(),
+            act: Some(Actor {
+                sub: actor.clone(),
+                act: subject.content.act.map(Box::new),
+            }),
+            extra: subject.content.extra,
+        };
+
+        let signing_keys = state.signing_keys.clone();
+        let access_token = state
+            .signing_pool
+            .run(move || AccessToken::create(&signing_keys, jwt_claims, content))
+            .instrument(info_span!("token_signing"))
+            .await??;
+
+        if let Some(audit) = &state.audit {
+            audit.record(AuditEvent::TokenExchanged {
+                user_id,
+                session_id,
+                actor,
+                access_token_jti: jti,
+            });
+        }
+
+        Ok(TokenExchangeResponse {
+            access_token: access_token.raw,
+            issued_token_type: ACCESS_TOKEN_TYPE,
+            token_type: "Bearer",
+            expires_in,
+            scope,
+        })
+    }
+
+    /// The requested scopes the subject token was granted, or all of them when none are
+    /// requested; `None` if that leaves nothing.
+    fn narrow_scope(granted: &str, requested: Option<&str>) -> Option<String> {
+        let granted: Vec<&str> = granted.split_whitespace().collect();
+        let Some(requested) = requested else {
+            return Some(granted.join(" "));
+        };
+        let mut narrowed: Vec<&str> = Vec::new();
+        for scope in requested.split_whitespace() {
+            if granted.contains(&scope) && !narrowed.contains(&scope) {
+                narrowed.push(scope);
+            }
+        }
+        if narrowed.is_empty() {
+            None
+        } else {
+            Some(narrowed.join(" "))
+        }
+    }
+
+    #[instrument(name = "userinfo", skip_all, fields(user_id = Empty))]
+    pub async fn userinfo(
+        req: HttpRequest,
+        state: web::Data<AppState>,
+    ) -> Result<HttpResponse, Error> {
+        let claims = profile_claims(&state, authorization(&req)).await?;
+        Ok(HttpResponse::Ok().json(claims))
+    }
+
+    /// OIDC UserInfo for the access token's subject, limited to the scopes it was granted.
+    async fn profile_claims(
+        state: &AppState,
+        authorization: Option<&str>,
+    ) -> Result<Map<String, Value>, MyError> {
+        let token = bearer_token(authorization)
+            .ok_or(MyError::InvalidToken)?
+            .to_string();
+        let decoded = resolve_access_token(state, token)
+            .await?
+            .ok_or(MyError::InvalidToken)?;
+        let scopes: Vec<&str> = decoded.content.scope.split_whitespace().collect();
+        if !scopes.contains(&"openid") {
+            return Err(MyError::InsufficientScope);
+        }
+        let subject = decoded.claims.sub.ok_or(MyError::InvalidToken)?;
+        Span::current().record("user_id", subject.as_str());
+
+        // a deleted user's tokens are no longer good for anything
+        let user = match db::get_user_by_id(&state.pool, &subject).await {
+            Err(MyError::NotFound) => return Err(MyError::InvalidToken),
+            result => result?,
+        };
+        let mut claims = Map::new();
+        claims.insert("sub".to_string(), Value::String(subject));
+        if scopes.contains(&"profile") {
+            claims.insert("name".to_string(), Value::String(user.name.clone()));
+            let attributes = if state.claims.needs_attributes() {
+                db::get_user_attributes(&state.pool, &user.id).await?
+            } else {
+                HashMap::new()
             };
+            claims.extend(state.claims.id_token_claims(&user.attributes(attributes)));
+        }
+        if scopes.contains(&"email") {
+            claims.insert("email".to_string(), Value::String(user.email));
+        }
+        Ok(claims)
+    }
 
-            println!(
-                "Actix-web Db time {}ms Password hash {}ms Token creation {}ms.",
-                time_db.as_millis(),
-                time_hash.as_millis(),
-                time_token.as_millis()
-            );
+    /// Verifies a signed access token or looks up an opaque one; `None` if it is neither valid
+    /// nor known.
+    async fn resolve_access_token(
+        state: &AppState,
+        token: String,
+    ) -> Result<Option<DecodedClaims<AccessClaims>>, MyError> {
+        // opaque handles are base64url, so only JWTs contain dots
+        if token.contains('.') {
+            let validation = state.auth.validation(&state.signing_keys);
+            let signing_keys = state.signing_keys.clone();
+            let decoded = state
+                .signing_pool
+                .run(move || tokens::decode_token(&signing_keys, &validation, &token))
+                .await?;
+            return Ok(decoded.ok());
+        }
+        let claims = db::find_access_token(&state.pool, &tokens::opaque_token_hash(&token)).await?;
+        Ok(claims.and_then(|claims| serde_json::from_str(&claims).ok()))
+    }
+
+    /// RFC 7662 token introspection, for the resource servers registered with `introspection`.
+    #[instrument(name = "introspect", skip_all, fields(client_id = Empty))]
+    pub async fn introspect(
+        req: HttpRequest,
+        form: web::Form<IntrospectionRequest>,
+        state: web::Data<AppState>,
+    ) -> Result<HttpResponse, Error> {
+        let client = state
+            .clients
+            .authenticate(authorization(&req))
+            .ok()
+            .flatten()
+            .filter(|client| client.introspection)
+            .ok_or(MyError::InvalidClient)?;
+        Span::current().record("client_id", client.client_id.as_str());
+        let response = match resolve_access_token(&state, form.into_inner().token).await? {
+            Some(decoded) => {
+                let mut response = json!(decoded);
+                response["active"] = Value::Bool(true);
+                response
+            }
+            None => json!({ "active": false }),
+        };
+        Ok(HttpResponse::Ok().json(response))
+    }
+
+    fn authorization(req: &HttpRequest) -> Option<&str> {
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+    }
 
-            Ok(HttpResponse::Ok().json(response))
+    fn bearer_token(authorization: Option<&str>) -> Option<&str> {
+        let (scheme, token) = authorization?.split_once(' ')?;
+        if scheme.eq_ignore_ascii_case("Bearer") {
+            Some(token.trim())
+        } else {
+            None
         }
     }
+
+    /// Sends form encoded `/token` requests, i.e. RFC 8693 token exchanges, to `exchange_token`.
+    pub fn is_form_request(ctx: &GuardContext) -> bool {
+        ctx.head()
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"))
+    }
+
+    pub async fn healthz() -> HttpResponse {
+        HttpResponse::Ok().json(json!({ "status": "ok" }))
+    }
+
+    /// The keys tokens are verified with, for resource servers to fetch.
+    pub async fn jwks(state: web::Data<AppState>) -> web::Json<JwkSet> {
+        web::Json(state.signing_keys.jwks())
+    }
+
+    pub async fn export_metrics(state: web::Data<AppState>) -> HttpResponse {
+        let (content_type, body) = state.metrics.render(&state.pool, &state.signing_pool);
+        HttpResponse::Ok().content_type(content_type).body(body)
+    }
+
+    pub async fn readyz(state: web::Data<AppState>) -> HttpResponse {
+        let readiness = health::readiness(
+            &state.pool,
+            &state.signing_check,
+            &state.signing_pool,
+            &state.shutdown,
+        )
+        .await;
+        if readiness.ready {
+            HttpResponse::Ok().json(readiness)
+        } else {
+            HttpResponse::ServiceUnavailable().json(readiness)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::narrow_scope;
+
+        #[test]
+        fn narrow_scope_keeps_only_granted_scopes() {
+            let granted = "openid profile email";
+            assert_eq!(
+                narrow_scope(granted, None).as_deref(),
+                Some("openid profile email")
+            );
+            assert_eq!(
+                narrow_scope(granted, Some("email admin openid email")).as_deref(),
+                Some("email openid")
+            );
+            assert_eq!(narrow_scope(granted, Some("admin")), None);
+            assert_eq!(narrow_scope(granted, Some("")), None);
+        }
+    }
+}
+
+pub struct AppState {
+    pool: deadpool_postgres::Pool,
+    signing_keys: Arc<SigningKeys>,
+    signing_check: SigningKeyCheck,
+    user_cache: Option<Arc<UserCache>>,
+    signing_pool: BlockingPool,
+    auth: AuthConfig,
+    claims: ClaimsConfig,
+    clients: Clients,
+    id_token_encryption: Arc<IdTokenEncryption>,
+    shutdown: Arc<Shutdown>,
+    metrics: Metrics,
+    audit: Option<AuditLog>,
 }
 
-use ::config::Config;
-use actix_web::{web, App, HttpServer};
+use actix_web::dev::Service;
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::{guard, web, App, HttpServer};
+use clap::Parser;
 use dotenv::dotenv;
-use handlers::logon_user;
+use handlers::{
+    exchange_token, export_metrics, healthz, introspect, is_form_request, jwks, logon_user, readyz,
+    userinfo,
+};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio_postgres::NoTls;
+use tracing::{info, Instrument};
 
-use crate::config::SimpleAuthConfig;
+use crate::audit::AuditLog;
+use crate::auth::clients::Clients;
+use crate::auth::encryption::IdTokenEncryption;
+use crate::auth::keys::SigningKeys;
+use crate::auth::mapper::ClaimsConfig;
+use crate::blocking::BlockingPool;
+use crate::cache::UserCache;
+use crate::config::{AuthConfig, Cli, SimpleAuthConfig};
+use crate::health::SigningKeyCheck;
+use crate::logging::REQUEST_ID_HEADER;
+use crate::metrics::Metrics;
+use crate::shutdown::Shutdown;
+use crate::tls::ReloadingCertResolver;
+
+const ACCESS_TOKEN_PURGE_INTERVAL: Duration = Duration::from_secs(10 * 60);
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    let config_ = Config::builder()
-        .add_source(::config::Environment::default().separator("__"))
-        .build()
-        .unwrap();
+    let cli = Cli::parse();
+    let config =
+        SimpleAuthConfig::load(&cli).expect("Should have been able to load the configuration");
+    let validation = config.validate();
+    if cli.check_config {
+        println!("{:#?}", config.redacted());
+    }
+    if let Err(errors) = validation {
+        for error in errors {
+            eprintln!("Invalid configuration: {}", error);
+        }
+        std::process::exit(1);
+    }
+    if cli.check_config {
+        println!("Configuration OK");
+        return Ok(());
+    }
+    logging::init(&config.logging, config.telemetry.as_ref());
 
-    let config: SimpleAuthConfig = config_.try_deserialize().unwrap();
+    let pg_tls = config.pg_tls.as_ref().map(|pg_tls| {
+        tls::pg_connector(pg_tls).expect("Should have been able to set up Postgres TLS")
+    });
+    let pg_config = config.pg_config();
+    let pool = match pg_tls {
+        Some(ref connector) => pg_config.builder(connector.clone()),
+        None => pg_config.builder(NoTls),
+    }
+    .unwrap()
+    .build()
+    .unwrap();
 
-    let pool = config.pg.builder(NoTls).unwrap().build().unwrap();
+    let signing_keys =
+        SigningKeys::load(&config.signing).expect("Should have been able to load the signing keys");
+    let id_token_encryption = IdTokenEncryption::load(&config.id_token_encryption)
+        .expect("Should have been able to load the ID token encryption keys");
 
-    let encoding_key = jsonwebtoken::EncodingKey::from_rsa_pem(include_bytes!("private_key.pem"))
-        .expect("Should have been able to read the file");
+    let metrics = Metrics::new();
+    let user_cache = config
+        .user_cache
+        .as_ref()
+        .map(|cache_config| Arc::new(UserCache::new(cache_config, metrics.user_cache_counters())));
+    if let (Some(cache_config), Some(cache)) = (&config.user_cache, &user_cache) {
+        if cache_config.listen_invalidations {
+            let pg_config = pg_config.get_pg_config().unwrap();
+            let cache = cache.clone();
+            let pg_tls = pg_tls.clone();
+            actix_web::rt::spawn(async move {
+                match pg_tls {
+                    Some(connector) => {
+                        cache::listen_for_invalidations(pg_config, connector, cache).await
+                    }
+                    None => cache::listen_for_invalidations(pg_config, NoTls, cache).await,
+                }
+            });
+        }
+    }
 
+    let audit = match config.audit {
+        Some(ref audit_config) => {
+            let sink = audit::open_sink(audit_config, &pool)
+                .await
+                .expect("Should have been able to open the audit sink");
+            Some(AuditLog::spawn(
+                sink,
+                audit_config.queue_capacity,
+                metrics.audit_dropped_counter(),
+            ))
+        }
+        None => None,
+    };
+
+    let shutdown = Shutdown::new(config.shutdown.clone());
+    let signing_keys = Arc::new(signing_keys);
+    let app_state = web::Data::new(AppState {
+        pool,
+        signing_check: SigningKeyCheck::new(signing_keys.clone()),
+        signing_keys,
+        user_cache,
+        signing_pool: BlockingPool::new(&config.signing_pool),
+        auth: config.auth,
+        claims: config.claims,
+        clients: Clients::new(&config.clients),
+        id_token_encryption: Arc::new(id_token_encryption),
+        shutdown: shutdown.clone(),
+        metrics,
+        audit,
+    });
+
+    let pool = app_state.pool.clone();
+    let audit = app_state.audit.clone();
+    actix_web::rt::spawn(db::purge_expired_access_tokens(
+        pool.clone(),
+        ACCESS_TOKEN_PURGE_INTERVAL,
+    ));
+
+    let app_shutdown = shutdown.clone();
     let server = HttpServer::new(move || {
+        let shutdown = app_shutdown.clone();
         App::new()
-            .app_data(web::Data::new((pool.clone(), encoding_key.clone())))
-            .service(web::resource("/token").route(web::post().to(logon_user)))
+            .app_data(app_state.clone())
+            // ask keep-alive clients to reconnect elsewhere while we are draining
+            .wrap_fn(move |req, srv| {
+                let draining = shutdown.is_draining();
+                let response = srv.call(req);
+                async move {
+                    let mut response = response.await?;
+                    if draining {
+                        response
+                            .headers_mut()
+                            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+                    }
+                    Ok(response)
+                }
+            })
+            .service(
+                web::resource("/token")
+                    .route(
+                        web::post()
+                            .guard(guard::fn_guard(is_form_request))
+                            .to(exchange_token),
+                    )
+                    .route(web::post().to(logon_user)),
+            )
+            .service(web::resource("/healthz").route(web::get().to(healthz)))
+            .service(web::resource("/readyz").route(web::get().to(readyz)))
+            .service(web::resource("/metrics").route(web::get().to(export_metrics)))
+            .service(web::resource("/.well-known/jwks.json").route(web::get().to(jwks)))
+            .service(web::resource("/userinfo").route(web::get().to(userinfo)))
+            .service(web::resource("/introspect").route(web::post().to(introspect)))
+            .wrap_fn(|req, srv| {
+                let request_id = logging::request_id(
+                    req.headers()
+                        .get(REQUEST_ID_HEADER)
+                        .and_then(|value| value.to_str().ok()),
+                );
+                let parent = telemetry::parent_context(|name| {
+                    req.headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                });
+                let span =
+                    logging::request_span(&request_id, req.method().as_str(), req.path(), parent);
+                let start = Instant::now();
+                let response = span.in_scope(|| srv.call(req));
+                async move {
+                    let mut response = response.await?;
+                    logging::log_response(response.status().as_u16(), start);
+                    if let Ok(value) = HeaderValue::from_str(&request_id) {
+                        response
+                            .headers_mut()
+                            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                    }
+                    Ok(response)
+                }
+                .instrument(span)
+            })
     })
-    .bind(config.server_addr.clone())?
-    .run();
+    // signals are handled by `Shutdown` so readiness can fail before we stop accepting
+    .disable_signals()
+    .shutdown_timeout(shutdown.drain_timeout().as_secs());
+    let (server, scheme) = match config.tls {
+        Some(tls_config) => {
+            let resolver = ReloadingCertResolver::new(tls_config)
+                .expect("Should have been able to load the TLS certificate");
+            resolver.clone().watch();
+            let server =
+                server.bind_rustls_021(config.server_addr.clone(), resolver.server_config())?;
+            (server, "https")
+        }
+        None => (server.bind(config.server_addr.clone())?, "http"),
+    };
+    let server = server.run();
 
-    println!(
-        "Actix-web simple auth open for e-Business at http://{}/ DB pool size {}",
+    let server_handle = server.handle();
+    let signal_shutdown = shutdown.clone();
+    actix_web::rt::spawn(async move {
+        signal_shutdown.wait_for_signal().await;
+        server_handle.stop(true).await;
+    });
+
+    info!(
+        "Actix-web simple auth open for e-Business at {}://{}/ DB pool size {}",
+        scheme,
         config.server_addr,
         config.pg.pool.unwrap().max_size,
     );
 
-    server.await
+    server.await?;
+    // the Postgres sink still needs the pool
+    if let Some(audit) = audit {
+        audit.flush().await;
+    }
+    pool.close();
+    info!("Actix-web simple auth stopped");
+    telemetry::shutdown();
+    Ok(())
 }
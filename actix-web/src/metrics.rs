@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+use deadpool_postgres::Pool;
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::blocking::BlockingPool;
+use crate::errors::MyError;
+
+pub struct Metrics {
+    registry: Registry,
+    login_phase_seconds: HistogramVec,
+    logins: IntCounterVec,
+    user_cache_lookups: IntCounterVec,
+    db_pool_connections: IntGaugeVec,
+    signing_pool_tasks: IntGaugeVec,
+    audit_events_dropped: IntCounter,
+}
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("simple_auth".to_string()), None).unwrap();
+        let login_phase_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "login_phase_seconds",
+                "Time spent per /token phase: user lookup, password hash, token signing",
+            )
+            // 0.25ms up to ~4s
+            .buckets(exponential_buckets(0.00025, 2.0, 15).unwrap()),
+            &["phase"],
+        )
+        .unwrap();
+        let logins = IntCounterVec::new(
+            Opts::new("logins_total", "/token requests by outcome"),
+            &["outcome"],
+        )
+        .unwrap();
+        let user_cache_lookups = IntCounterVec::new(
+            Opts::new("user_cache_lookups_total", "User cache lookups by result"),
+            &["result"],
+        )
+        .unwrap();
+        let db_pool_connections = IntGaugeVec::new(
+            Opts::new("db_pool_connections", "Postgres pool connections by state"),
+            &["state"],
+        )
+        .unwrap();
+        let signing_pool_tasks = IntGaugeVec::new(
+            Opts::new(
+                "signing_pool_tasks",
+                "Hashing and signing tasks, queued or running",
+            ),
+            &["state"],
+        )
+        .unwrap();
+        let audit_events_dropped = IntCounter::new(
+            "audit_events_dropped_total",
+            "Audit events dropped because the audit queue was full",
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(login_phase_seconds.clone()))
+            .unwrap();
+        registry.register(Box::new(logins.clone())).unwrap();
+        registry
+            .register(Box::new(user_cache_lookups.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(db_pool_connections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(signing_pool_tasks.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(audit_events_dropped.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            login_phase_seconds,
+            logins,
+            user_cache_lookups,
+            db_pool_connections,
+            signing_pool_tasks,
+            audit_events_dropped,
+        }
+    }
+
+    pub fn observe_phase(&self, phase: &str, elapsed: Duration) {
+        self.login_phase_seconds
+            .with_label_values(&[phase])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_login<T>(&self, result: &Result<T, MyError>) {
+        self.logins
+            .with_label_values(&[login_outcome(result)])
+            .inc();
+    }
+
+    /// Counters handed to the user cache, which increments them itself.
+    pub fn user_cache_counters(&self) -> (IntCounter, IntCounter) {
+        (
+            self.user_cache_lookups.with_label_values(&["hit"]),
+            self.user_cache_lookups.with_label_values(&["miss"]),
+        )
+    }
+
+    pub fn audit_dropped_counter(&self) -> IntCounter {
+        self.audit_events_dropped.clone()
+    }
+
+    /// Samples the pool gauges and renders everything in the Prometheus text format.
+    pub fn render(&self, pool: &Pool, signing_pool: &BlockingPool) -> (String, String) {
+        let status = pool.status();
+        let connections = &self.db_pool_connections;
+        connections
+            .with_label_values(&["size"])
+            .set(status.size as i64);
+        connections
+            .with_label_values(&["available"])
+            .set(status.available.max(0) as i64);
+        connections
+            .with_label_values(&["waiting"])
+            .set((-status.available).max(0) as i64);
+        connections
+            .with_label_values(&["max"])
+            .set(status.max_size as i64);
+        self.signing_pool_tasks
+            .with_label_values(&["queued"])
+            .set(signing_pool.queue_depth() as i64);
+        self.signing_pool_tasks
+            .with_label_values(&["running"])
+            .set(signing_pool.in_flight() as i64);
+
+        let encoder = TextEncoder::new();
+        let mut buffer = Vec::new();
+        encoder
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        (
+            encoder.format_type().to_string(),
+            String::from_utf8(buffer).unwrap(),
+        )
+    }
+}
+
+pub fn login_outcome<T>(result: &Result<T, MyError>) -> &'static str {
+    match result {
+        Ok(_) => "success",
+        Err(MyError::IncorrectPassword) => "bad_password",
+        Err(MyError::NotFound) => "unknown_user",
+        Err(MyError::InvalidTarget) => "invalid_target",
+        Err(MyError::InvalidClient) => "invalid_client",
+        Err(_) => "error",
+    }
+}
@@ -0,0 +1,64 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::info;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// Time between failing readiness and closing the listeners, so the load balancer
+    /// (e.g. Kubernetes endpoints) has stopped routing to us before we stop accepting.
+    pub readiness_grace_secs: u64,
+    /// How long in-flight requests get to finish once the listeners are closed.
+    pub drain_timeout_secs: u64,
+}
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            readiness_grace_secs: 5,
+            drain_timeout_secs: 30,
+        }
+    }
+}
+
+pub struct Shutdown {
+    config: ShutdownConfig,
+    draining: AtomicBool,
+}
+impl Shutdown {
+    pub fn new(config: ShutdownConfig) -> Arc<Self> {
+        Arc::new(Shutdown {
+            config,
+            draining: AtomicBool::new(false),
+        })
+    }
+
+    /// True from the first SIGTERM/ctrl-c on; readiness reports failure from then on.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Waits for SIGTERM or ctrl-c, starts draining, and returns after the readiness grace
+    /// period, when the caller stops the server.
+    pub async fn wait_for_signal(&self) {
+        let mut sigterm =
+            signal(SignalKind::terminate()).expect("Should be able to handle SIGTERM");
+        tokio::select! {
+            _ = sigterm.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+        info!(
+            readiness_grace_secs = self.config.readiness_grace_secs,
+            "Shutting down, draining before closing the listeners"
+        );
+        self.draining.store(true, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_secs(self.config.readiness_grace_secs)).await;
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.drain_timeout_secs)
+    }
+}
@@ -0,0 +1,161 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig,
+};
+use serde::Deserialize;
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tracing::{info, warn};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    /// How often the certificate files are checked for changes.
+    #[serde(default = "default_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+}
+
+fn default_reload_interval_secs() -> u64 {
+    30
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PgTlsConfig {
+    /// Only trust this CA for the database server instead of the public web PKI roots.
+    pub ca_cert_path: Option<String>,
+}
+
+/// Serves whatever certificate is currently on disk, so renewals don't need a restart.
+pub struct ReloadingCertResolver {
+    config: TlsConfig,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+impl ReloadingCertResolver {
+    pub fn new(config: TlsConfig) -> io::Result<Arc<Self>> {
+        let certified_key = load_certified_key(&config)?;
+        Ok(Arc::new(ReloadingCertResolver {
+            config,
+            current: RwLock::new(Arc::new(certified_key)),
+        }))
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        let mut server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        server_config
+    }
+
+    /// Polls the modification times of the cert and key, swapping in the new pair when
+    /// either changes. A broken pair is logged and the previous certificate kept.
+    pub fn watch(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut last_modified = self.last_modified();
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(self.config.reload_interval_secs.max(1)));
+            loop {
+                interval.tick().await;
+                let modified = self.last_modified();
+                if modified == last_modified {
+                    continue;
+                }
+                match load_certified_key(&self.config) {
+                    Ok(certified_key) => {
+                        *self.current.write().unwrap() = Arc::new(certified_key);
+                        last_modified = modified;
+                        info!(cert_path = %self.config.cert_path, "Reloaded TLS certificate");
+                    }
+                    Err(err) => warn!(
+                        cert_path = %self.config.cert_path,
+                        error = %err,
+                        "Keeping current TLS certificate, reload failed"
+                    ),
+                }
+            }
+        });
+    }
+
+    fn last_modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((
+            modified(&self.config.cert_path)?,
+            modified(&self.config.key_path)?,
+        ))
+    }
+}
+
+/// Fails when the configured certificate and key can't be loaded.
+pub fn check(config: &TlsConfig) -> io::Result<()> {
+    load_certified_key(config).map(|_| ())
+}
+
+fn load_certified_key(config: &TlsConfig) -> io::Result<CertifiedKey> {
+    let certs = load_certs(&config.cert_path)?;
+    let mut reader = BufReader::new(File::open(&config.key_path)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => break PrivateKey(key),
+            Some(_) => continue,
+            None => {
+                return Err(invalid_data(format!(
+                    "no private key in {}",
+                    config.key_path
+                )))
+            }
+        }
+    };
+    let signing_key = sign::any_supported_type(&key).map_err(invalid_data)?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!("no certificates in {}", path)));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// TLS connector for the Postgres pool; pins the configured CA when there is one.
+pub fn pg_connector(config: &PgTlsConfig) -> io::Result<MakeRustlsConnect> {
+    let mut root_store = RootCertStore::empty();
+    match config.ca_cert_path {
+        Some(ref ca_cert_path) => {
+            for cert in load_certs(ca_cert_path)? {
+                root_store.add(&cert).map_err(invalid_data)?;
+            }
+        }
+        None => root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        })),
+    }
+    let client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    Ok(MakeRustlsConnect::new(client_config))
+}
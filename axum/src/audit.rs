@@ -0,0 +1,220 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{SecondsFormat, Utc};
+use deadpool_postgres::Pool;
+use futures::future::{BoxFuture, FutureExt};
+use prometheus::IntCounter;
+use serde::{Deserialize, Serialize};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+
+/// Append-only table created by `db/create.sql`.
+const INSERT_AUDIT_EVENT: &str = "INSERT INTO audit_events (occurred_at, event, details) \
+     VALUES ($1::TEXT::timestamptz, $2, $3::TEXT::jsonb);";
+
+pub type SinkError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditSinkKind {
+    Postgres,
+    File,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub sink: AuditSinkKind,
+    /// JSON-lines file the `file` sink appends to.
+    pub file_path: Option<PathBuf>,
+    /// Events waiting to be written; further events are dropped and counted.
+    pub queue_capacity: usize,
+}
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            sink: AuditSinkKind::Postgres,
+            file_path: None,
+            queue_capacity: 1024,
+        }
+    }
+}
+impl AuditConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.queue_capacity == 0 {
+            return Err("audit.queue_capacity must be positive".to_string());
+        }
+        if matches!(self.sink, AuditSinkKind::File) && self.file_path.is_none() {
+            return Err("audit.file_path is required for the file sink".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Events the servers record. Password changes are written to `audit_events` by the
+/// `users_password_changed` trigger in `db/create.sql` instead, whatever changed the password,
+/// so the file sink doesn't see them.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    LoginSucceeded {
+        email: String,
+        user_id: String,
+    },
+    LoginFailed {
+        email: String,
+        reason: &'static str,
+    },
+    TokenIssued {
+        user_id: String,
+        session_id: String,
+        id_token_jti: String,
+        access_token_jti: String,
+    },
+    TokenExchanged {
+        user_id: String,
+        session_id: String,
+        actor: String,
+        access_token_jti: String,
+    },
+}
+impl AuditEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuditEvent::LoginSucceeded { .. } => "login_succeeded",
+            AuditEvent::LoginFailed { .. } => "login_failed",
+            AuditEvent::TokenIssued { .. } => "token_issued",
+            AuditEvent::TokenExchanged { .. } => "token_exchanged",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    /// RFC 3339 time the event happened, not when it was written.
+    pub occurred_at: String,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+/// Durable destination for audit records, driven by a single writer task.
+pub trait AuditSink: Send {
+    fn write<'a>(&'a mut self, record: &'a AuditRecord) -> BoxFuture<'a, Result<(), SinkError>>;
+}
+
+pub struct PostgresSink {
+    pool: Pool,
+}
+impl AuditSink for PostgresSink {
+    fn write<'a>(&'a mut self, record: &'a AuditRecord) -> BoxFuture<'a, Result<(), SinkError>> {
+        async move {
+            let details = serde_json::to_string(&record.event)?;
+            let client = self.pool.get().await?;
+            let stmt = client.prepare_cached(INSERT_AUDIT_EVENT).await?;
+            client
+                .execute(
+                    &stmt,
+                    &[&record.occurred_at, &record.event.name(), &details],
+                )
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+pub struct FileSink {
+    file: File,
+}
+impl FileSink {
+    pub async fn open(path: &Path) -> io::Result<FileSink> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(FileSink { file })
+    }
+}
+impl AuditSink for FileSink {
+    fn write<'a>(&'a mut self, record: &'a AuditRecord) -> BoxFuture<'a, Result<(), SinkError>> {
+        async move {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            self.file.write_all(&line).await?;
+            // a record only counts once it survives a crash
+            self.file.sync_data().await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+pub async fn open_sink(config: &AuditConfig, pool: &Pool) -> io::Result<Box<dyn AuditSink>> {
+    match config.sink {
+        AuditSinkKind::Postgres => Ok(Box::new(PostgresSink { pool: pool.clone() })),
+        AuditSinkKind::File => {
+            let path = config.file_path.as_deref().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "audit.file_path is not set")
+            })?;
+            Ok(Box::new(FileSink::open(path).await?))
+        }
+    }
+}
+
+enum Message {
+    Record(AuditRecord),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Hands events to the writer task so requests never wait on the sink.
+#[derive(Clone)]
+pub struct AuditLog {
+    sender: mpsc::Sender<Message>,
+    dropped: IntCounter,
+}
+impl AuditLog {
+    pub fn spawn(sink: Box<dyn AuditSink>, queue_capacity: usize, dropped: IntCounter) -> Self {
+        let (sender, receiver) = mpsc::channel(queue_capacity);
+        tokio::spawn(write_records(sink, receiver));
+        AuditLog { sender, dropped }
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        let name = event.name();
+        let record = AuditRecord {
+            occurred_at: Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            event,
+        };
+        if self.sender.try_send(Message::Record(record)).is_err() {
+            self.dropped.inc();
+            error!(event = name, "Audit queue full, event dropped");
+        }
+    }
+
+    /// Waits until every event recorded so far has been written.
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        if self.sender.send(Message::Flush(done)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+}
+
+async fn write_records(mut sink: Box<dyn AuditSink>, mut receiver: mpsc::Receiver<Message>) {
+    while let Some(message) = receiver.recv().await {
+        match message {
+            Message::Record(record) => {
+                if let Err(err) = sink.write(&record).await {
+                    error!(error = %err, event = record.event.name(), "Failed to write audit record");
+                }
+            }
+            Message::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
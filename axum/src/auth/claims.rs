@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::auth::errors::*;
@@ -7,6 +7,23 @@ use crate::auth::errors::*;
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AccessClaims {
     pub session_id: String,
+    /// Granted scopes, space separated.
+    #[serde(default)]
+    pub scope: String,
+    /// Who acts on the subject's behalf, for tokens obtained by token exchange.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+    /// Claims added by the configured claims mapper.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// RFC 8693 `act` claim; `act` nests the earlier actors of a delegation chain.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Actor {
+    pub sub: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Box<Actor>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -15,14 +32,85 @@ pub struct IdClaims {
     pub name: String,
     pub email: String,
     pub at_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub c_hash: Option<String>,
+    /// Echoes the relying party's authentication request `nonce`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_time: Option<u64>,
+    /// Client the token was issued to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub azp: Option<String>,
+    /// Authentication context class the user authenticated with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acr: Option<String>,
+    /// Claims added by the configured claims mapper.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 impl IdClaims {
     pub fn with_at_hash(self, at_hash: String) -> Self {
         IdClaims {
-            id: self.id,
-            name: self.name,
-            email: self.email,
             at_hash: Some(at_hash),
+            ..self
+        }
+    }
+    pub fn with_c_hash(self, c_hash: String) -> Self {
+        IdClaims {
+            c_hash: Some(c_hash),
+            ..self
+        }
+    }
+    pub fn with_nonce(self, nonce: String) -> Self {
+        IdClaims {
+            nonce: Some(nonce),
+            ..self
+        }
+    }
+    pub fn authenticated_now(self) -> Self {
+        IdClaims {
+            auth_time: Some(duration_since_epoch().as_secs()),
+            ..self
+        }
+    }
+    pub fn with_authorized_party(self, azp: String) -> Self {
+        IdClaims {
+            azp: Some(azp),
+            ..self
+        }
+    }
+    pub fn with_acr(self, acr: String) -> Self {
+        IdClaims {
+            acr: Some(acr),
+            ..self
+        }
+    }
+    pub fn with_extra(self, extra: Map<String, Value>) -> Self {
+        IdClaims { extra, ..self }
+    }
+}
+
+/// `aud` as RFC 7519 allows it: a single string or an array of strings.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+impl Audience {
+    /// A single audience is written as a plain string, which more verifiers accept.
+    pub fn from_vec(mut audiences: Vec<String>) -> Self {
+        if audiences.len() == 1 {
+            Audience::Single(audiences.remove(0))
+        } else {
+            Audience::Multiple(audiences)
+        }
+    }
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            Audience::Single(audience) => vec![audience],
+            Audience::Multiple(audiences) => audiences,
         }
     }
 }
@@ -34,7 +122,7 @@ pub struct JwtClaim {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub sub: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub aud: Option<String>,
+    pub aud: Option<Audience>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub exp: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -67,17 +155,39 @@ impl JwtClaim {
             jti: self.jti,
         }
     }
-    pub fn with_audience(self, audience: String) -> Self {
+    pub fn with_audiences(self, audiences: Vec<String>) -> Self {
         JwtClaim {
             iss: self.iss,
             sub: self.sub,
-            aud: Some(audience),
+            aud: Some(Audience::from_vec(audiences)),
+            exp: self.exp,
+            nbf: self.nbf,
+            iat: self.iat,
+            jti: self.jti,
+        }
+    }
+    pub fn with_subject(self, subject: String) -> Self {
+        JwtClaim {
+            iss: self.iss,
+            sub: Some(subject),
+            aud: self.aud,
             exp: self.exp,
             nbf: self.nbf,
             iat: self.iat,
             jti: self.jti,
         }
     }
+    pub fn with_jwt_id(self, jwt_id: String) -> Self {
+        JwtClaim {
+            iss: self.iss,
+            sub: self.sub,
+            aud: self.aud,
+            exp: self.exp,
+            nbf: self.nbf,
+            iat: self.iat,
+            jti: Some(jwt_id),
+        }
+    }
     pub fn expires_in(self, seconds: u64) -> Self {
         JwtClaim {
             iss: self.iss,
@@ -89,6 +199,17 @@ impl JwtClaim {
             jti: self.jti,
         }
     }
+    pub fn not_before(self, seconds_from_now: u64) -> Self {
+        JwtClaim {
+            iss: self.iss,
+            sub: self.sub,
+            aud: self.aud,
+            exp: self.exp,
+            nbf: Some(duration_since_epoch().as_secs() + seconds_from_now),
+            iat: self.iat,
+            jti: self.jti,
+        }
+    }
     pub fn issued_now(self) -> Self {
         JwtClaim {
             iss: self.iss,
@@ -108,20 +229,43 @@ impl JwtClaim {
     }
 }
 
+/// A verified token: the registered claims plus its content.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DecodedClaims<T> {
+    #[serde(flatten)]
+    pub claims: JwtClaim,
+    #[serde(flatten)]
+    pub content: T,
+}
+
 pub struct JwtClaimWithContent<T: Serialize> {
     content: T,
     claim: JwtClaim,
 }
 impl<T: Serialize> JwtClaimWithContent<T> {
+    /// The registered claims and the content as one JSON object; a content field that
+    /// would overwrite a registered claim is an error.
     pub fn as_json_value(&self) -> Result<Value> {
-        let json_string = self.as_json()?;
-        let json_value = serde_json::from_str::<Value>(&json_string)?;
-        Ok(json_value)
+        let mut claims = to_json_object(&self.claim)?;
+        for (name, value) in to_json_object(&self.content)? {
+            if claims.contains_key(&name) {
+                return Err(Error {
+                    message: format!("claim '{}' is set by both the token and its content", name),
+                });
+            }
+            claims.insert(name, value);
+        }
+        Ok(Value::Object(claims))
     }
-    pub fn as_json(&self) -> Result<String> {
-        let json_claim = serde_json::to_string(&self.claim)?;
-        let json_content = serde_json::to_string(&self.content)?;
-        Ok(merge_json(&json_claim, &json_content))
+}
+
+fn to_json_object<T: Serialize>(value: &T) -> Result<Map<String, Value>> {
+    match serde_json::to_value(value)? {
+        Value::Object(map) => Ok(map),
+        Value::Null => Ok(Map::new()),
+        other => Err(Error {
+            message: format!("claims must serialize to a JSON object, not {}", other),
+        }),
     }
 }
 
@@ -131,18 +275,61 @@ fn duration_since_epoch() -> Duration {
         .expect("This is the time before time.")
 }
 
-fn merge_json(first: &str, second: &str) -> String {
-    let ft = first.trim();
-    let st = second.trim();
-    if ft.is_empty() {
-        st.to_string()
-    } else if st.is_empty() {
-        ft.to_string()
-    } else {
-        let mut f = ft[..ft.len() - 1].to_string();
-        let s = &st[1..];
-        f.push(',');
-        f.push_str(s);
-        f
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn claim() -> JwtClaim {
+        JwtClaim::empty()
+            .with_subject("42".to_string())
+            .with_audiences(vec!["simple-auth".to_string()])
+    }
+
+    #[test]
+    fn content_merges_into_the_registered_claims() {
+        let value = claim()
+            .with_content(json!({ "scope": "openid" }))
+            .as_json_value()
+            .unwrap();
+        assert_eq!(value["sub"], "42");
+        assert_eq!(value["aud"], "simple-auth");
+        assert_eq!(value["scope"], "openid");
+    }
+
+    #[test]
+    fn content_may_not_overwrite_a_registered_claim() {
+        let err = claim()
+            .with_content(json!({ "sub": "admin" }))
+            .as_json_value()
+            .unwrap_err();
+        assert!(err.message.contains("'sub'"), "{}", err);
+
+        // claims the mapper adds are checked the same way
+        let mut extra = Map::new();
+        extra.insert("aud".to_string(), json!("elsewhere"));
+        let content = AccessClaims {
+            session_id: "s".to_string(),
+            scope: String::new(),
+            act: None,
+            extra,
+        };
+        assert!(claim().with_content(content).as_json_value().is_err());
+    }
+
+    #[test]
+    fn empty_content_leaves_the_registered_claims() {
+        let expected = claim().with_content(()).as_json_value().unwrap();
+        assert_eq!(expected, json!({ "sub": "42", "aud": "simple-auth" }));
+        assert_eq!(
+            claim().with_content(json!({})).as_json_value().unwrap(),
+            expected
+        );
+        assert_eq!(
+            claim().with_content(Value::Null).as_json_value().unwrap(),
+            expected
+        );
+        assert!(claim().with_content(json!([1])).as_json_value().is_err());
     }
 }
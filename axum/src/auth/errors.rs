@@ -5,6 +5,11 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub struct Error {
     pub message: String,
 }
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
 impl From<serde_json::Error> for Error {
     fn from(value: serde_json::Error) -> Self {
         Self {
@@ -34,3 +39,24 @@ impl From<jsonwebtoken::errors::Error> for Error {
         }
     }
 }
+impl From<rsa::Error> for Error {
+    fn from(value: rsa::Error) -> Self {
+        Self {
+            message: value.to_string(),
+        }
+    }
+}
+impl From<rsa::pkcs8::spki::Error> for Error {
+    fn from(value: rsa::pkcs8::spki::Error) -> Self {
+        Self {
+            message: value.to_string(),
+        }
+    }
+}
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self {
+            message: value.to_string(),
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use base64::{engine::general_purpose, Engine as _};
+use elliptic_curve::sec1::ToEncodedPoint;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::pkcs8::spki::{self, DecodePublicKey, ObjectIdentifier, SubjectPublicKeyInfoRef};
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPublicKey;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::claims::JwtClaim;
+use crate::auth::errors::*;
+use crate::auth::tokens::decode_token;
+
+// the RSA-4096 pair baked into the binary, used when no key files are configured
+const EMBEDDED_PRIVATE_KEY: &[u8] = include_bytes!("../private_key.pem");
+const EMBEDDED_PUBLIC_KEY: &[u8] = include_bytes!("../public_key.pem");
+
+// id-Ed25519, RFC 8410
+const ED25519_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SigningConfig {
+    #[serde(default = "default_algorithm")]
+    pub algorithm: Algorithm,
+    /// PKCS#8 PEM private key; RSA, EC (P-256/P-384) or Ed25519 depending on `algorithm`.
+    pub private_key_path: Option<String>,
+    /// SPKI PEM public key matching `private_key_path`.
+    pub public_key_path: Option<String>,
+    pub key_id: Option<String>,
+    /// Shared secret for the HS256/HS384/HS512 modes, meant for closed internal deployments only.
+    pub secret: Option<String>,
+}
+impl Default for SigningConfig {
+    fn default() -> Self {
+        SigningConfig {
+            algorithm: default_algorithm(),
+            private_key_path: None,
+            public_key_path: None,
+            key_id: None,
+            secret: None,
+        }
+    }
+}
+
+fn default_algorithm() -> Algorithm {
+    Algorithm::RS256
+}
+
+/// The keys tokens are signed and verified with, and the header announcing them.
+pub struct SigningKeys {
+    pub header: Header,
+    pub encoding_key: EncodingKey,
+    pub decoding_key: DecodingKey,
+    /// The public key, `None` for the HS modes, whose secret must not be published.
+    jwk: Option<Jwk>,
+}
+impl SigningKeys {
+    pub fn load(config: &SigningConfig) -> Result<SigningKeys> {
+        let (encoding_key, decoding_key, jwk) = match config.algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let (encoding_key, decoding_key) = load_secret(config)?;
+                (encoding_key, decoding_key, None)
+            }
+            _ => {
+                let (encoding_key, decoding_key, jwk) = load_key_pair(config)?;
+                (encoding_key, decoding_key, Some(jwk))
+            }
+        };
+
+        let mut header = Header::new(config.algorithm);
+        header.kid = config.key_id.clone();
+
+        let keys = SigningKeys {
+            header,
+            encoding_key,
+            decoding_key,
+            jwk,
+        };
+        keys.check_pair()?;
+        Ok(keys)
+    }
+
+    /// Fails when the verification key can't verify what the signing key signs.
+    pub fn check_pair(&self) -> Result<()> {
+        let probe = JwtClaim::empty().issued_now().expires_in(60);
+        let token = jsonwebtoken::encode(&self.header, &probe, &self.encoding_key)?;
+        decode_token::<JwtClaim>(self, &self.validation(), &token)?;
+        Ok(())
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.header.alg
+    }
+
+    /// Only checks the algorithm and expiry; callers add issuer, audience and leeway.
+    pub fn validation(&self) -> Validation {
+        Validation::new(self.algorithm())
+    }
+
+    /// The JWK Set resource servers verify tokens with; empty in the HS modes.
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self.jwk.iter().cloned().collect(),
+        }
+    }
+}
+
+/// A public signing key (RFC 7517), with the members RFC 7518 section 6 defines for its type.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Jwk {
+    pub kty: &'static str,
+    #[serde(rename = "use")]
+    pub key_use: &'static str,
+    pub alg: Algorithm,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crv: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+/// The secret has to be at least as long as the HMAC output, as RFC 7518 section 3.2 requires.
+fn load_secret(config: &SigningConfig) -> Result<(EncodingKey, DecodingKey)> {
+    let min_length = match config.algorithm {
+        Algorithm::HS384 => 48,
+        Algorithm::HS512 => 64,
+        _ => 32,
+    };
+    match config.secret {
+        Some(ref secret) if secret.len() >= min_length => Ok((
+            EncodingKey::from_secret(secret.as_bytes()),
+            DecodingKey::from_secret(secret.as_bytes()),
+        )),
+        Some(_) => Err(Error {
+            message: format!(
+                "{:?} signing needs a secret of at least {} bytes",
+                config.algorithm, min_length
+            ),
+        }),
+        None => Err(Error {
+            message: format!("{:?} signing needs a secret", config.algorithm),
+        }),
+    }
+}
+
+fn load_key_pair(config: &SigningConfig) -> Result<(EncodingKey, DecodingKey, Jwk)> {
+    let (private_pem, public_pem) = match (&config.private_key_path, &config.public_key_path) {
+        (Some(private_path), Some(public_path)) => {
+            (std::fs::read(private_path)?, std::fs::read(public_path)?)
+        }
+        (None, None) if config.algorithm == Algorithm::RS256 => {
+            (EMBEDDED_PRIVATE_KEY.to_vec(), EMBEDDED_PUBLIC_KEY.to_vec())
+        }
+        _ => {
+            return Err(Error {
+                message: format!(
+                    "{:?} signing needs both private_key_path and public_key_path",
+                    config.algorithm
+                ),
+            })
+        }
+    };
+
+    let (encoding_key, decoding_key) = match config.algorithm {
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512 => (
+            EncodingKey::from_rsa_pem(&private_pem)?,
+            DecodingKey::from_rsa_pem(&public_pem)?,
+        ),
+        Algorithm::ES256 | Algorithm::ES384 => (
+            EncodingKey::from_ec_pem(&private_pem)?,
+            DecodingKey::from_ec_pem(&public_pem)?,
+        ),
+        Algorithm::EdDSA => (
+            EncodingKey::from_ed_pem(&private_pem)?,
+            DecodingKey::from_ed_pem(&public_pem)?,
+        ),
+        other => {
+            return Err(Error {
+                message: format!("{:?} is not an asymmetric signing algorithm", other),
+            })
+        }
+    };
+    let mut jwk = public_jwk(config.algorithm, &public_pem)?;
+    jwk.kid = config.key_id.clone();
+    Ok((encoding_key, decoding_key, jwk))
+}
+
+/// The JWK of an SPKI PEM public key (or PKCS#1 for RSA) of the type `algorithm` signs with.
+fn public_jwk(algorithm: Algorithm, public_pem: &[u8]) -> Result<Jwk> {
+    let pem = std::str::from_utf8(public_pem).map_err(|err| Error {
+        message: format!("public key: {}", err),
+    })?;
+    let mut jwk = Jwk {
+        kty: "",
+        key_use: "sig",
+        alg: algorithm,
+        kid: None,
+        crv: None,
+        x: None,
+        y: None,
+        n: None,
+        e: None,
+    };
+    match algorithm {
+        Algorithm::ES256 => {
+            let point = p256::PublicKey::from_public_key_pem(pem)?.to_encoded_point(false);
+            jwk.kty = "EC";
+            jwk.crv = Some("P-256");
+            jwk.x = point.x().map(|x| base64url(x));
+            jwk.y = point.y().map(|y| base64url(y));
+        }
+        Algorithm::ES384 => {
+            let point = p384::PublicKey::from_public_key_pem(pem)?.to_encoded_point(false);
+            jwk.kty = "EC";
+            jwk.crv = Some("P-384");
+            jwk.x = point.x().map(|x| base64url(x));
+            jwk.y = point.y().map(|y| base64url(y));
+        }
+        Algorithm::EdDSA => {
+            let key = Ed25519PublicKey::from_public_key_pem(pem)?;
+            jwk.kty = "OKP";
+            jwk.crv = Some("Ed25519");
+            jwk.x = Some(base64url(&key.0));
+        }
+        _ => {
+            let key = RsaPublicKey::from_public_key_pem(pem)
+                .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+                .map_err(|err| Error {
+                    message: format!("public key: {}", err),
+                })?;
+            jwk.kty = "RSA";
+            jwk.n = Some(base64url(&key.n().to_bytes_be()));
+            jwk.e = Some(base64url(&key.e().to_bytes_be()));
+        }
+    }
+    Ok(jwk)
+}
+
+/// The raw key of an Ed25519 SPKI, which no dependency here decodes on its own.
+struct Ed25519PublicKey(Vec<u8>);
+impl TryFrom<SubjectPublicKeyInfoRef<'_>> for Ed25519PublicKey {
+    type Error = spki::Error;
+
+    fn try_from(spki: SubjectPublicKeyInfoRef<'_>) -> spki::Result<Self> {
+        spki.algorithm.assert_algorithm_oid(ED25519_OID)?;
+        match spki.subject_public_key.as_bytes() {
+            Some(key) if key.len() == 32 => Ok(Ed25519PublicKey(key.to_vec())),
+            _ => Err(spki::Error::KeyMalformed),
+        }
+    }
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const P256_PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEP+yoW6QHQb96pHlBTCYsw+1LbycF
+hNHmw5ub36wy1ZFwW/PYB1ovMrPaixjKQcHt8hcwzCXctW2NElycCIkTPA==
+-----END PUBLIC KEY-----";
+    const ED25519_PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MCowBQYDK2VwAyEALY9XPhXS+xhdoCOQww5qXpXntfLbmcnzb6attDKOZzI=
+-----END PUBLIC KEY-----";
+
+    #[test]
+    fn jwks_publishes_the_embedded_rsa_key() {
+        let config = SigningConfig {
+            key_id: Some("embedded".to_string()),
+            ..SigningConfig::default()
+        };
+        let keys = SigningKeys::load(&config).unwrap();
+        let jwks = serde_json::to_value(keys.jwks()).unwrap();
+        let jwk = &jwks["keys"][0];
+        assert_eq!(jwk["kty"], "RSA");
+        assert_eq!(jwk["alg"], "RS256");
+        assert_eq!(jwk["use"], "sig");
+        assert_eq!(jwk["kid"], "embedded");
+        assert_eq!(jwk["e"], "AQAB");
+        // 4096 bits, base64url without padding
+        assert_eq!(jwk["n"].as_str().unwrap().len(), 683);
+        assert!(jwk.get("crv").is_none());
+    }
+
+    #[test]
+    fn jwk_follows_the_key_type() {
+        let jwk = public_jwk(Algorithm::ES256, P256_PUBLIC_KEY.as_bytes()).unwrap();
+        assert_eq!((jwk.kty, jwk.crv), ("EC", Some("P-256")));
+        assert_eq!(
+            jwk.x.as_deref(),
+            Some("P-yoW6QHQb96pHlBTCYsw-1LbycFhNHmw5ub36wy1ZE")
+        );
+        assert_eq!(
+            jwk.y.as_deref(),
+            Some("cFvz2AdaLzKz2osYykHB7fIXMMwl3LVtjRJcnAiJEzw")
+        );
+
+        let jwk = public_jwk(Algorithm::EdDSA, ED25519_PUBLIC_KEY.as_bytes()).unwrap();
+        assert_eq!((jwk.kty, jwk.crv), ("OKP", Some("Ed25519")));
+        assert_eq!(
+            jwk.x.as_deref(),
+            Some("LY9XPhXS-xhdoCOQww5qXpXntfLbmcnzb6attDKOZzI")
+        );
+        assert_eq!(jwk.y, None);
+
+        // a key of another type than the algorithm signs with
+        assert!(public_jwk(Algorithm::EdDSA, P256_PUBLIC_KEY.as_bytes()).is_err());
+        assert!(public_jwk(Algorithm::ES384, P256_PUBLIC_KEY.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn jwks_is_empty_for_shared_secrets() {
+        let config = SigningConfig {
+            algorithm: Algorithm::HS256,
+            secret: Some("0123456789abcdef0123456789abcdef".to_string()),
+            ..SigningConfig::default()
+        };
+        assert!(SigningKeys::load(&config).unwrap().jwks().keys.is_empty());
+    }
+}
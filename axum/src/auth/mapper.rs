@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// Claims the server sets itself; a rule may not override them.
+const RESERVED_CLAIMS: [&str; 19] = [
+    "iss",
+    "sub",
+    "aud",
+    "exp",
+    "nbf",
+    "iat",
+    "jti",
+    "id",
+    "name",
+    "email",
+    "at_hash",
+    "c_hash",
+    "nonce",
+    "auth_time",
+    "azp",
+    "acr",
+    "session_id",
+    "scope",
+    "act",
+];
+
+/// Attributes every user has, straight from the `users` row.
+const USER_COLUMNS: [&str; 3] = ["id", "name", "email"];
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClaimRule {
+    /// Name of the claim in the token.
+    pub claim: String,
+    /// Takes the value from a `users` column or a `user_attributes` entry...
+    pub attribute: Option<String>,
+    /// ...or always uses this value.
+    pub value: Option<Value>,
+    /// Emits an array even for a single value, e.g. for groups.
+    #[serde(default)]
+    pub multi_valued: bool,
+}
+impl ClaimRule {
+    fn apply(&self, attributes: &HashMap<String, Vec<String>>) -> Option<Value> {
+        if let Some(ref value) = self.value {
+            return Some(value.clone());
+        }
+        let values = attributes.get(self.attribute.as_deref()?)?;
+        match values.as_slice() {
+            [] => None,
+            [single] if !self.multi_valued => Some(Value::String(single.clone())),
+            _ => Some(Value::from(values.clone())),
+        }
+    }
+}
+
+/// Extra claims per token type, e.g. organization, locale or groups.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ClaimsConfig {
+    pub id_token: Vec<ClaimRule>,
+    pub access_token: Vec<ClaimRule>,
+}
+impl ClaimsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for rule in self.id_token.iter().chain(&self.access_token) {
+            if RESERVED_CLAIMS.contains(&rule.claim.as_str()) {
+                return Err(format!("claims: '{}' is set by the server", rule.claim));
+            }
+            if rule.attribute.is_some() == rule.value.is_some() {
+                return Err(format!(
+                    "claims: '{}' needs either an attribute or a value",
+                    rule.claim
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any rule reads attributes beyond the `users` columns.
+    pub fn needs_attributes(&self) -> bool {
+        self.id_token
+            .iter()
+            .chain(&self.access_token)
+            .filter_map(|rule| rule.attribute.as_deref())
+            .any(|attribute| !USER_COLUMNS.contains(&attribute))
+    }
+
+    pub fn id_token_claims(&self, attributes: &HashMap<String, Vec<String>>) -> Map<String, Value> {
+        map_claims(&self.id_token, attributes)
+    }
+
+    pub fn access_token_claims(
+        &self,
+        attributes: &HashMap<String, Vec<String>>,
+    ) -> Map<String, Value> {
+        map_claims(&self.access_token, attributes)
+    }
+}
+
+// rules whose attribute the user does not have are left out
+fn map_claims(
+    rules: &[ClaimRule],
+    attributes: &HashMap<String, Vec<String>>,
+) -> Map<String, Value> {
+    rules
+        .iter()
+        .filter_map(|rule| {
+            rule.apply(attributes)
+                .map(|value| (rule.claim.clone(), value))
+        })
+        .collect()
+}
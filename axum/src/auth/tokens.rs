@@ -1,8 +1,43 @@
-use jsonwebtoken::{encode, EncodingKey, Header};
+use base64::{engine::general_purpose, Engine as _};
+use jsonwebtoken::{decode, encode, Algorithm, EncodingKey, Header, Validation};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 use crate::auth::claims::*;
 use crate::auth::errors::*;
+use crate::auth::keys::SigningKeys;
+
+/// How `at_hash` is computed.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AtHashFormat {
+    /// OIDC Core 3.1.3.6, what standard verifiers expect.
+    #[default]
+    Oidc,
+    /// The format earlier releases issued, for clients that still check it.
+    Legacy,
+}
+
+/// What the access token handed to a client is.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessTokenFormat {
+    /// A signed JWT resource servers verify themselves.
+    #[default]
+    Jwt,
+    /// A random handle only `/introspect` resolves, so no claims travel with it.
+    Opaque,
+}
+
+/// How a pair is issued, besides its claims.
+#[derive(Debug, Default)]
+pub struct IssueOptions<'a> {
+    pub at_hash_format: AtHashFormat,
+    /// The code issued alongside the tokens in the code flow, hashed into `c_hash`.
+    pub authorization_code: Option<&'a str>,
+    /// Hand out this opaque handle (see `opaque_token`) instead of a signed access token.
+    pub opaque_access_token: Option<String>,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct IdToken {
@@ -20,6 +55,28 @@ pub struct AccessToken {
     pub raw: String,
 }
 
+impl AccessToken {
+    /// A signed access token on its own, as token exchange issues them.
+    pub fn create(
+        keys: &SigningKeys,
+        claims: JwtClaim,
+        content: AccessClaims,
+    ) -> Result<AccessToken> {
+        let raw = create_token(
+            &keys.encoding_key,
+            &keys.header,
+            claims.clone(),
+            content.clone(),
+        )?;
+        Ok(AccessToken {
+            header: keys.header.clone(),
+            claims,
+            content,
+            raw,
+        })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TokenPair {
     pub id_token: IdToken,
@@ -27,37 +84,47 @@ pub struct TokenPair {
 }
 impl TokenPair {
     pub fn create(
-        encoding_key: &EncodingKey,
-        header: &Header,
-        common_claims: JwtClaim,
+        keys: &SigningKeys,
+        id_jwt_claims: JwtClaim,
+        access_jwt_claims: JwtClaim,
         id_claims: IdClaims,
         access_claims: AccessClaims,
+        options: IssueOptions,
     ) -> Result<TokenPair> {
-        let at_tkn = create_token(
-            encoding_key,
-            header,
-            common_claims.clone(),
-            access_claims.clone(),
-        )
-        .unwrap();
-        let at_hash = hash_token(&at_tkn)?;
-        let id_claims_with_hash = id_claims.with_at_hash(at_hash);
+        let encoding_key = &keys.encoding_key;
+        let header = &keys.header;
+        let at_tkn = match options.opaque_access_token {
+            Some(handle) => handle,
+            None => create_token(
+                encoding_key,
+                header,
+                access_jwt_claims.clone(),
+                access_claims.clone(),
+            )?,
+        };
+        let at_hash = match options.at_hash_format {
+            AtHashFormat::Oidc => oidc_hash(header.alg, &at_tkn),
+            AtHashFormat::Legacy => legacy_hash_token(&at_tkn)?,
+        };
+        let mut id_claims_with_hash = id_claims.with_at_hash(at_hash);
+        if let Some(code) = options.authorization_code {
+            id_claims_with_hash = id_claims_with_hash.with_c_hash(oidc_hash(header.alg, code));
+        }
         let id_tkn = create_token(
             encoding_key,
             header,
-            common_claims.clone(),
+            id_jwt_claims.clone(),
             id_claims_with_hash.clone(),
-        )
-        .unwrap();
+        )?;
         let access_token = AccessToken {
             header: header.clone(),
-            claims: common_claims.clone(),
+            claims: access_jwt_claims,
             content: access_claims,
             raw: at_tkn,
         };
         let id_token = IdToken {
             header: header.clone(),
-            claims: common_claims,
+            claims: id_jwt_claims,
             content: id_claims_with_hash,
             raw: id_tkn,
         };
@@ -79,6 +146,48 @@ fn create_token<T: Serialize>(
     Ok(token)
 }
 
+/// Checks the signature of a token signed with `keys` and its claims against `validation`.
+pub fn decode_token<T: DeserializeOwned>(
+    keys: &SigningKeys,
+    validation: &Validation,
+    token: &str,
+) -> Result<T> {
+    let data = decode::<T>(token, &keys.decoding_key, validation)?;
+    Ok(data.claims)
+}
+
+/// A random reference token that carries no claims itself.
+pub fn opaque_token() -> String {
+    use rand::RngCore;
+
+    let mut handle = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut handle);
+    general_purpose::URL_SAFE_NO_PAD.encode(handle)
+}
+
+/// What opaque tokens are stored and looked up by, so a database leak exposes no usable tokens.
+pub fn opaque_token_hash(token: &str) -> String {
+    hex::encode(sha256(token.as_bytes()))
+}
+
+/// OIDC Core `at_hash`/`c_hash`: the left half of the hash of the ASCII value, using the hash
+/// function of the signing `alg`, base64url encoded without padding.
+pub fn oidc_hash(alg: Algorithm, value: &str) -> String {
+    use sha2::{Digest, Sha256, Sha384, Sha512};
+
+    let hash = match alg {
+        Algorithm::HS384 | Algorithm::RS384 | Algorithm::PS384 | Algorithm::ES384 => {
+            Sha384::digest(value.as_bytes()).to_vec()
+        }
+        // Ed25519 signs with SHA-512 internally, so OIDC uses it for EdDSA too
+        Algorithm::HS512 | Algorithm::RS512 | Algorithm::PS512 | Algorithm::EdDSA => {
+            Sha512::digest(value.as_bytes()).to_vec()
+        }
+        _ => Sha256::digest(value.as_bytes()).to_vec(),
+    };
+    general_purpose::URL_SAFE_NO_PAD.encode(&hash[..hash.len() / 2])
+}
+
 fn base64_encode(input: &str) -> Result<String> {
     let encoded = base64_encode_u8(input.as_bytes())?;
     let result = String::from_utf8(encoded)?;
@@ -86,9 +195,7 @@ fn base64_encode(input: &str) -> Result<String> {
 }
 
 fn base64_encode_u8(input: &[u8]) -> Result<Vec<u8>> {
-    use base64::{engine::general_purpose, Engine as _};
-    let mut buf = Vec::new();
-    buf.resize(input.len() * 4 / 3 + 4, 0);
+    let mut buf = vec![0; input.len() * 4 / 3 + 4];
     let bytes_written = general_purpose::STANDARD.encode_slice(input, &mut buf)?;
     buf.truncate(bytes_written);
     Ok(buf)
@@ -103,7 +210,7 @@ fn sha256(input: &[u8]) -> Vec<u8> {
     result[..].to_vec()
 }
 
-fn hash_token(input: &str) -> Result<String> {
+fn legacy_hash_token(input: &str) -> Result<String> {
     let enc = base64_encode_u8(base64_encode(input)?.as_bytes())?;
     let hash = sha256(&enc);
     let mid = hash.len() / 2;
@@ -111,3 +218,43 @@ fn hash_token(input: &str) -> Result<String> {
     let result = String::from_utf8(base64_encode_u8(hash_2)?)?;
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the access token and authorization code from the OIDC Core examples (A.3 and A.4)
+    const ACCESS_TOKEN: &str = "jHkWEdUXMU1BwAsC4vtUsZwnNvTIxEl0z9K3vx5KF0Y";
+    const CODE: &str = "Qcb0Orv1zh30vL1MPRsbm-diHiMwcLyZvn1arpZv-Jxf_11jnpEX3Tgfvk";
+
+    #[test]
+    fn oidc_hash_matches_the_spec_examples() {
+        assert_eq!(
+            oidc_hash(Algorithm::RS256, ACCESS_TOKEN),
+            "77QmUPtjPfzWtF2AnpK9RQ"
+        );
+        assert_eq!(oidc_hash(Algorithm::RS256, CODE), "LDktKdoQak3Pk0cnXxCltA");
+    }
+
+    #[test]
+    fn oidc_hash_follows_the_signing_alg() {
+        assert_eq!(
+            oidc_hash(Algorithm::ES384, ACCESS_TOKEN),
+            "jtAeDp945y1dDqU3nkIVGNZP1HjH_MFs"
+        );
+        for alg in [Algorithm::RS512, Algorithm::EdDSA] {
+            assert_eq!(
+                oidc_hash(alg, ACCESS_TOKEN),
+                "q7nS86GgvvFaZkzALLWqJYaJIKw2wCDAVfCAsm5CrBM"
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_hash_is_unchanged() {
+        assert_eq!(
+            legacy_hash_token(ACCESS_TOKEN).unwrap(),
+            "CAVJggZ4hnGc6xSZ1X75UA=="
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+
+use crate::errors::MyError;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct BlockingPoolConfig {
+    /// Number of CPU-bound tasks (password hashing, token signing) running at once.
+    pub max_concurrency: usize,
+    /// Tasks allowed to wait for a slot before requests are turned away.
+    pub max_queue: usize,
+}
+impl Default for BlockingPoolConfig {
+    fn default() -> Self {
+        BlockingPoolConfig {
+            max_concurrency: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4),
+            max_queue: 1024,
+        }
+    }
+}
+
+/// Runs CPU-bound work on tokio's blocking threads so it doesn't stall the async workers,
+/// bounded by a semaphore so a burst of logins queues up instead of spawning unbounded threads.
+pub struct BlockingPool {
+    permits: Arc<Semaphore>,
+    max_concurrency: usize,
+    max_queue: usize,
+    queued: AtomicUsize,
+}
+impl BlockingPool {
+    pub fn new(config: &BlockingPoolConfig) -> Self {
+        let max_concurrency = config.max_concurrency.max(1);
+        BlockingPool {
+            permits: Arc::new(Semaphore::new(max_concurrency)),
+            max_concurrency,
+            max_queue: config.max_queue,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    pub async fn run<F, R>(&self, task: F) -> Result<R, MyError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let permit = {
+            let _queued = QueuedGuard::enter(&self.queued, self.max_queue)?;
+            self.permits
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("the blocking pool semaphore is never closed")
+        };
+        let result = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            task()
+        })
+        .await?;
+        Ok(result)
+    }
+
+    /// Tasks waiting for a free slot.
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Tasks currently running on a blocking thread.
+    pub fn in_flight(&self) -> usize {
+        self.max_concurrency - self.permits.available_permits()
+    }
+}
+
+/// Keeps the queue depth right when a waiting request is dropped (e.g. the client went away).
+struct QueuedGuard<'a> {
+    queued: &'a AtomicUsize,
+}
+impl<'a> QueuedGuard<'a> {
+    fn enter(queued: &'a AtomicUsize, max_queue: usize) -> Result<Self, MyError> {
+        if queued.fetch_add(1, Ordering::Relaxed) >= max_queue {
+            queued.fetch_sub(1, Ordering::Relaxed);
+            return Err(MyError::Overloaded);
+        }
+        Ok(QueuedGuard { queued })
+    }
+}
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
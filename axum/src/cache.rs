@@ -0,0 +1,224 @@
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{stream, StreamExt};
+use lru::LruCache;
+use prometheus::IntCounter;
+use serde::Deserialize;
+use tokio_postgres::tls::MakeTlsConnect;
+use tokio_postgres::{AsyncMessage, Socket};
+use tracing::{info, warn};
+
+use crate::models::User;
+
+/// Channel the `users` table trigger in `db/create.sql` notifies on, with the email as payload.
+const INVALIDATION_CHANNEL: &str = "user_changed";
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct UserCacheConfig {
+    pub capacity: usize,
+    pub ttl_secs: u64,
+    #[serde(default)]
+    pub negative_ttl_secs: u64,
+    /// Without it a changed password keeps working until the cached row expires.
+    #[serde(default = "default_listen_invalidations")]
+    pub listen_invalidations: bool,
+}
+
+fn default_listen_invalidations() -> bool {
+    true
+}
+
+struct CachedUser {
+    // None records that the email is unknown (negative caching)
+    user: Option<User>,
+    expires_at: Instant,
+}
+
+struct Entries {
+    users: LruCache<String, CachedUser>,
+    /// Bumped by every invalidation; lookups take it before reading the database.
+    generation: u64,
+    /// The generation each recently invalidated email was invalidated at.
+    invalidated: LruCache<String, u64>,
+    /// Lookups that started before this generation may have missed an invalidation whose
+    /// record has since been evicted or cleared, so their rows are not cached.
+    floor: u64,
+}
+
+pub struct UserCache {
+    entries: Mutex<Entries>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    hits: IntCounter,
+    misses: IntCounter,
+}
+impl UserCache {
+    pub fn new(config: &UserCacheConfig, (hits, misses): (IntCounter, IntCounter)) -> Self {
+        let capacity = NonZeroUsize::new(config.capacity).unwrap_or(NonZeroUsize::MIN);
+        UserCache {
+            entries: Mutex::new(Entries {
+                users: LruCache::new(capacity),
+                generation: 0,
+                invalidated: LruCache::new(capacity),
+                floor: 0,
+            }),
+            ttl: Duration::from_secs(config.ttl_secs),
+            negative_ttl: Duration::from_secs(config.negative_ttl_secs),
+            hits,
+            misses,
+        }
+    }
+
+    /// `Some(None)` is a cached miss: the user is known not to exist.
+    pub fn get(&self, email: &str) -> Option<Option<User>> {
+        let mut entries = self.entries.lock().unwrap();
+        let cached = match entries.users.get(email) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.user.clone()),
+            Some(_) => {
+                entries.users.pop(email);
+                None
+            }
+            None => None,
+        };
+        match cached {
+            Some(_) => self.hits.inc(),
+            None => self.misses.inc(),
+        }
+        cached
+    }
+
+    /// To be taken before reading a user from the database and handed to `insert`.
+    pub fn generation(&self) -> u64 {
+        self.entries.lock().unwrap().generation
+    }
+
+    /// Caches a row read after `generation` was taken, unless the email was invalidated
+    /// since: the row may predate the change that invalidated it.
+    pub fn insert(&self, email: &str, user: Option<User>, generation: u64) {
+        let ttl = if user.is_some() {
+            self.ttl
+        } else {
+            self.negative_ttl
+        };
+        if ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let invalidated_since = generation < entries.floor
+            || entries
+                .invalidated
+                .peek(email)
+                .is_some_and(|&invalidated| invalidated > generation);
+        if invalidated_since {
+            return;
+        }
+        entries.users.put(
+            email.to_string(),
+            CachedUser {
+                user,
+                expires_at: Instant::now() + ttl,
+            },
+        );
+    }
+
+    pub fn invalidate(&self, email: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.generation += 1;
+        let generation = entries.generation;
+        entries.users.pop(email);
+        if let Some((evicted, invalidated)) =
+            entries.invalidated.push(email.to_string(), generation)
+        {
+            if evicted != email {
+                entries.floor = entries.floor.max(invalidated);
+            }
+        }
+    }
+
+    /// Drops every entry, for when invalidations may have been missed.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.generation += 1;
+        entries.floor = entries.generation;
+        entries.users.clear();
+        entries.invalidated.clear();
+    }
+}
+
+/// Evicts users whose row changed (password change, disable, delete) as soon as
+/// Postgres notifies us, rather than waiting for the TTL to run out. Reconnects with
+/// backoff when the connection drops and clears the cache once listening again, since
+/// notifications sent in between are lost.
+pub async fn listen_for_invalidations<T>(
+    pg_config: tokio_postgres::Config,
+    tls: T,
+    cache: Arc<UserCache>,
+) where
+    T: MakeTlsConnect<Socket> + Clone,
+    T::Stream: Send + 'static,
+{
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        match listen(&pg_config, tls.clone(), &cache).await {
+            Ok(()) => {
+                delay = MIN_RECONNECT_DELAY;
+                warn!("User cache invalidation connection lost, reconnecting");
+            }
+            Err(err) => warn!(
+                error = %err,
+                retry_in_secs = delay.as_secs(),
+                "User cache invalidation listener failed to connect"
+            ),
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Applies notifications until the connection is lost.
+async fn listen<T>(
+    pg_config: &tokio_postgres::Config,
+    tls: T,
+    cache: &UserCache,
+) -> Result<(), tokio_postgres::Error>
+where
+    T: MakeTlsConnect<Socket>,
+    T::Stream: Send + 'static,
+{
+    let (client, mut connection) = pg_config.connect(tls).await?;
+
+    // the connection has to be polled for LISTEN to complete, so drive it separately
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+    tokio::spawn(async move {
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notification(notification)) => {
+                    if tx.send(notification).is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    warn!(error = %err, "User cache invalidation connection failed");
+                    break;
+                }
+            }
+        }
+    });
+
+    client
+        .batch_execute(&format!("LISTEN {};", INVALIDATION_CHANNEL))
+        .await?;
+    cache.clear();
+    info!("Listening for user cache invalidations");
+    while let Some(notification) = rx.recv().await {
+        cache.invalidate(notification.payload());
+    }
+    Ok(())
+}
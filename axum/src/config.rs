@@ -0,0 +1,332 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use deadpool_postgres::SslMode;
+use jsonwebtoken::Validation;
+use serde::Deserialize;
+
+use crate::audit::AuditConfig;
+use crate::auth::clients::ClientConfig;
+use crate::auth::encryption::{ClientEncryptionConfig, IdTokenEncryption};
+use crate::auth::keys::{SigningConfig, SigningKeys};
+use crate::auth::mapper::ClaimsConfig;
+use crate::auth::tokens::{AccessTokenFormat, AtHashFormat};
+use crate::blocking::BlockingPoolConfig;
+use crate::cache::UserCacheConfig;
+use crate::listen::ListenAddr;
+use crate::logging::LoggingConfig;
+use crate::shutdown::ShutdownConfig;
+use crate::telemetry::TelemetryConfig;
+use crate::tls::{self, PgTlsConfig, TlsConfig};
+
+const REDACTED: &str = "<redacted>";
+const MIN_CLIENT_SECRET_LEN: usize = 16;
+
+#[derive(Debug, Parser)]
+#[command(version = env!("RUST_WEB_DEV_VERSION"), about = "Simple authentication web service")]
+pub struct Cli {
+    /// TOML or YAML config file; environment variables and `--set` take precedence over it.
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Overrides a single setting, e.g. `--set pg.pool.max_size=20`.
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+    /// Validates the configuration, prints it with secrets redacted and exits.
+    #[arg(long)]
+    pub check_config: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SimpleAuthConfig {
+    /// One or more listen addresses, comma separated in `SERVER_ADDR`.
+    pub server_addr: Vec<String>,
+    pub pg: deadpool_postgres::Config,
+    pub user_cache: Option<UserCacheConfig>,
+    #[serde(default)]
+    pub signing_pool: BlockingPoolConfig,
+    #[serde(default)]
+    pub signing: SigningConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub claims: ClaimsConfig,
+    /// Confidential clients and resource servers, authenticating with HTTP Basic.
+    #[serde(default)]
+    pub clients: Vec<ClientConfig>,
+    /// Clients whose ID tokens are encrypted (JWE) to their public key.
+    #[serde(default)]
+    pub id_token_encryption: Vec<ClientEncryptionConfig>,
+    /// Serve HTTPS instead of plain HTTP when set.
+    pub tls: Option<TlsConfig>,
+    /// Connect to Postgres over TLS when set.
+    pub pg_tls: Option<PgTlsConfig>,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// Export traces over OTLP when set.
+    pub telemetry: Option<TelemetryConfig>,
+    /// Record authentication events when set.
+    pub audit: Option<AuditConfig>,
+}
+impl SimpleAuthConfig {
+    /// Layers, from lowest to highest precedence: config file, `.env`/environment, `--set`.
+    pub fn load(cli: &Cli) -> Result<SimpleAuthConfig, String> {
+        let mut builder = ::config::Config::builder();
+        if let Some(ref path) = cli.config {
+            builder = builder.add_source(::config::File::from(path.as_path()));
+        }
+        builder = builder.add_source(
+            ::config::Environment::default()
+                .separator("__")
+                .list_separator(",")
+                .with_list_parse_key("server_addr")
+                .with_list_parse_key("auth.audiences")
+                .with_list_parse_key("auth.scopes")
+                .try_parsing(true),
+        );
+        for item in &cli.overrides {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| format!("--set {} is not KEY=VALUE", item))?;
+            builder = builder
+                .set_override(key.trim(), value.trim())
+                .map_err(|err| err.to_string())?;
+        }
+        builder
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .map_err(|err| err.to_string())
+    }
+
+    /// Checks everything that would otherwise only fail once the server is up.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        if self.server_addr.is_empty() {
+            errors.push("server_addr is empty".to_string());
+        }
+        for addr in &self.server_addr {
+            if let Err(err) = addr.parse::<ListenAddr>() {
+                errors.push(format!("server_addr {}", err));
+            }
+        }
+        if let Err(err) = self.pg.get_pg_config() {
+            errors.push(format!("pg: {}", err));
+        }
+        if let Some(ref pool) = self.pg.pool {
+            if pool.max_size == 0 {
+                errors.push("pg.pool.max_size must be positive".to_string());
+            }
+        }
+        if let Some(ref user_cache) = self.user_cache {
+            if user_cache.capacity == 0 || user_cache.ttl_secs == 0 {
+                errors.push("user_cache.capacity and ttl_secs must be positive".to_string());
+            }
+        }
+        if self.signing_pool.max_concurrency == 0 {
+            errors.push("signing_pool.max_concurrency must be positive".to_string());
+        }
+        if let Err(err) = SigningKeys::load(&self.signing) {
+            errors.push(format!("signing: {}", err.message));
+        }
+        if let Err(err) = self.auth.validate() {
+            errors.push(err);
+        }
+        if let Err(err) = self.claims.validate() {
+            errors.push(err);
+        }
+        let mut client_ids: Vec<&str> = Vec::new();
+        for client in &self.clients {
+            if client.client_id.is_empty() || client_ids.contains(&client.client_id.as_str()) {
+                errors.push(format!(
+                    "clients: client_id '{}' is empty or not unique",
+                    client.client_id
+                ));
+            }
+            if client.secret.len() < MIN_CLIENT_SECRET_LEN {
+                errors.push(format!(
+                    "clients: the secret of '{}' is shorter than {} characters",
+                    client.client_id, MIN_CLIENT_SECRET_LEN
+                ));
+            }
+            if let Some(audience) = client
+                .exchange_audiences
+                .iter()
+                .find(|audience| !self.auth.audiences.contains(audience))
+            {
+                errors.push(format!(
+                    "clients: exchange audience '{}' of '{}' is not in auth.audiences",
+                    audience, client.client_id
+                ));
+            }
+            client_ids.push(&client.client_id);
+        }
+        if let Err(err) = IdTokenEncryption::load(&self.id_token_encryption) {
+            errors.push(err.message);
+        }
+        if let Some(ref tls_config) = self.tls {
+            if let Err(err) = tls::check(tls_config) {
+                errors.push(format!("tls: {}", err));
+            }
+        }
+        if let Some(ref pg_tls) = self.pg_tls {
+            if let Err(err) = tls::pg_connector(pg_tls) {
+                errors.push(format!("pg_tls: {}", err));
+            }
+            if matches!(self.pg.ssl_mode, Some(SslMode::Disable | SslMode::Prefer)) {
+                errors.push("pg_tls needs pg.ssl_mode unset or require".to_string());
+            }
+        }
+        if let Some(ref audit) = self.audit {
+            if let Err(err) = audit.validate() {
+                errors.push(err);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The Postgres settings to connect with. TLS is required whenever `pg_tls` is set, so a
+    /// server that doesn't offer it, or anyone stripping it on the way, can't make the pool
+    /// fall back to plaintext.
+    pub fn pg_config(&self) -> deadpool_postgres::Config {
+        let mut pg = self.pg.clone();
+        if self.pg_tls.is_some() {
+            pg.ssl_mode = Some(SslMode::Require);
+        }
+        pg
+    }
+
+    /// A copy that is safe to print.
+    pub fn redacted(&self) -> SimpleAuthConfig {
+        let mut config = self.clone();
+        if config.pg.password.is_some() {
+            config.pg.password = Some(REDACTED.to_string());
+        }
+        if config.signing.secret.is_some() {
+            config.signing.secret = Some(REDACTED.to_string());
+        }
+        for client in &mut config.clients {
+            client.secret = REDACTED.to_string();
+        }
+        config
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub issuer: String,
+    /// Audiences tokens may be issued for; the first one is used by default.
+    pub audiences: Vec<String>,
+    pub access_token_lifetime_secs: u64,
+    pub id_token_lifetime_secs: u64,
+    pub refresh_token_lifetime_secs: u64,
+    /// Scopes clients may request; all of them are granted when a request names none.
+    pub scopes: Vec<String>,
+    /// Clock skew tolerated when validating `exp` and `nbf` of incoming tokens.
+    pub leeway_secs: u64,
+    /// `legacy` keeps issuing the pre-OIDC `at_hash` for clients that still verify it.
+    pub at_hash_format: AtHashFormat,
+    /// `acr` of ID tokens issued after a password login.
+    pub password_acr: String,
+    /// Access token format for requests no registered client authenticates; clients can set
+    /// their own.
+    pub access_token_format: AccessTokenFormat,
+}
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            issuer: "https://example.com".to_string(),
+            audiences: vec!["simple-auth.example.com".to_string()],
+            scopes: vec![
+                "openid".to_string(),
+                "profile".to_string(),
+                "email".to_string(),
+            ],
+            access_token_lifetime_secs: 60 * 60,
+            id_token_lifetime_secs: 60 * 60,
+            refresh_token_lifetime_secs: 24 * 60 * 60,
+            leeway_secs: 60,
+            at_hash_format: AtHashFormat::Oidc,
+            password_acr: "urn:simple-auth:acr:password".to_string(),
+            access_token_format: AccessTokenFormat::Jwt,
+        }
+    }
+}
+impl AuthConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.issuer.starts_with("https://") && !self.issuer.starts_with("http://") {
+            return Err(format!(
+                "auth.issuer '{}' is not an http(s) URL",
+                self.issuer
+            ));
+        }
+        if self.audiences.is_empty() || self.audiences.iter().any(|aud| aud.is_empty()) {
+            return Err("auth.audiences needs at least one non-empty audience".to_string());
+        }
+        if self.access_token_lifetime_secs == 0 || self.id_token_lifetime_secs == 0 {
+            return Err("auth token lifetimes must be positive".to_string());
+        }
+        if self.refresh_token_lifetime_secs < self.access_token_lifetime_secs {
+            return Err(
+                "auth.refresh_token_lifetime_secs is shorter than the access token lifetime"
+                    .to_string(),
+            );
+        }
+        if self.leeway_secs >= self.access_token_lifetime_secs {
+            return Err("auth.leeway_secs exceeds the access token lifetime".to_string());
+        }
+        Ok(())
+    }
+
+    /// The audiences a token request asked for (RFC 8707 `resource`/`audience`), or the default
+    /// one when it asked for none. `None` if anything requested is not on the allow-list.
+    pub fn resolve_audiences(&self, requested: Vec<String>) -> Option<Vec<String>> {
+        if requested.is_empty() {
+            return Some(vec![self.audiences[0].clone()]);
+        }
+        let mut resolved: Vec<String> = Vec::with_capacity(requested.len());
+        for audience in requested {
+            if !self.audiences.contains(&audience) {
+                return None;
+            }
+            if !resolved.contains(&audience) {
+                resolved.push(audience);
+            }
+        }
+        Some(resolved)
+    }
+
+    /// The requested scopes that are on the allow-list, space separated; others are left out,
+    /// as OAuth 2.0 lets a server grant less than asked for.
+    pub fn resolve_scope(&self, requested: Option<&str>) -> String {
+        let Some(requested) = requested else {
+            return self.scopes.join(" ");
+        };
+        let mut granted: Vec<&str> = Vec::new();
+        for scope in requested.split_whitespace() {
+            if self.scopes.iter().any(|allowed| allowed == scope) && !granted.contains(&scope) {
+                granted.push(scope);
+            }
+        }
+        granted.join(" ")
+    }
+
+    /// Accepts tokens this server issued: signature, `exp`/`nbf` with leeway, issuer and
+    /// audience.
+    pub fn validation(&self, keys: &SigningKeys) -> Validation {
+        let mut validation = keys.validation();
+        validation.leeway = self.leeway_secs;
+        validation.validate_nbf = true;
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&self.audiences);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        validation
+    }
+}
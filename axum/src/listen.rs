@@ -0,0 +1,93 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+
+use hyper::server::accept::Accept;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{UnixListener, UnixStream};
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// One entry of `SERVER_ADDR`: `0.0.0.0:8781`, `[::]:8781` or `unix:/run/simple-auth.sock`.
+#[derive(Debug, Clone)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().strip_prefix(UNIX_PREFIX) {
+            Some(path) if !path.is_empty() => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            Some(_) => Err(format!("'{}' is missing a socket path", s)),
+            None => s
+                .trim()
+                .parse()
+                .map(ListenAddr::Tcp)
+                .map_err(|err| format!("'{}' is not a listen address: {}", s, err)),
+        }
+    }
+}
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// IPv6 sockets are made v6-only so `0.0.0.0` and `[::]` can be bound on the same port.
+pub fn bind_tcp(addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+pub struct UnixAccept {
+    listener: UnixListener,
+}
+impl UnixAccept {
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        // a socket file left behind by a previous run would make bind fail; anything else at the
+        // path is most likely a misconfiguration and is left alone
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(UnixAccept {
+            listener: UnixListener::bind(path)?,
+        })
+    }
+}
+impl Accept for UnixAccept {
+    type Conn = UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let (stream, _addr) = ready!(self.listener.poll_accept(cx))?;
+        Poll::Ready(Some(Ok(stream)))
+    }
+}
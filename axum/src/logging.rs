@@ -0,0 +1,94 @@
+use std::time::Instant;
+
+use serde::Deserialize;
+use tracing::{info, info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+use uuid::Uuid;
+
+use crate::telemetry::{self, TelemetryConfig};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// `tracing` filter directive such as `info` or `info,tokio_postgres=warn`; RUST_LOG wins.
+    pub level: String,
+}
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            format: LogFormat::Pretty,
+            level: "info".to_string(),
+        }
+    }
+}
+
+/// Sets up log output and, when `telemetry` is configured, OTLP trace export.
+pub fn init(config: &LoggingConfig, telemetry: Option<&TelemetryConfig>) {
+    let otel_layer = telemetry.map(|telemetry_config| {
+        telemetry::layer(telemetry_config).expect("Should have been able to set up trace export")
+    });
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.level));
+    let fmt_layer = match config.format {
+        LogFormat::Pretty => fmt::layer().pretty().boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(otel_layer)
+        .with(filter)
+        .with(fmt_layer)
+        .init();
+}
+
+/// Keeps the caller's `X-Request-Id` when it looks sane, otherwise makes up a new one.
+pub fn request_id(header: Option<&str>) -> String {
+    match header {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= 128
+                && id
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b)) =>
+        {
+            id.to_string()
+        }
+        _ => Uuid::new_v4().to_string(),
+    }
+}
+
+/// Span for one HTTP request, continuing the caller's trace when it sent one.
+pub fn request_span(
+    request_id: &str,
+    method: &str,
+    path: &str,
+    parent: opentelemetry::Context,
+) -> Span {
+    let span = info_span!("request", request_id, method, path);
+    span.set_parent(parent);
+    span
+}
+
+pub fn log_response(status: u16, start: Instant) {
+    info!(
+        status,
+        latency_ms = start.elapsed().as_secs_f64() * 1000.0,
+        "request finished"
+    );
+}
@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::trace::{self, Sampler, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use serde::Deserialize;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::Registry;
+
+const PROPAGATION_HEADERS: [&str; 2] = ["traceparent", "tracestate"];
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// OTLP/gRPC endpoint of the collector.
+    pub otlp_endpoint: String,
+    pub service_name: String,
+    /// Fraction of new traces to sample; traces started upstream follow the caller's decision.
+    pub sample_ratio: f64,
+}
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            otlp_endpoint: "http://localhost:4317".to_string(),
+            service_name: env!("CARGO_PKG_NAME").to_string(),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+/// Exports spans to the collector in batches and accepts W3C trace context from callers.
+pub fn layer(
+    config: &TelemetryConfig,
+) -> Result<OpenTelemetryLayer<Registry, Tracer>, opentelemetry::trace::TraceError> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(&config.otlp_endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                    config.sample_ratio,
+                ))))
+                .with_resource(Resource::new(vec![KeyValue::new(
+                    "service.name",
+                    config.service_name.clone(),
+                )])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// The caller's trace context from the `traceparent`/`tracestate` request headers.
+pub fn parent_context<'a>(header: impl Fn(&str) -> Option<&'a str>) -> Context {
+    let carrier: HashMap<String, String> = PROPAGATION_HEADERS
+        .iter()
+        .filter_map(|name| header(name).map(|value| (name.to_string(), value.to_string())))
+        .collect();
+    global::get_text_map_propagator(|propagator| propagator.extract(&carrier))
+}
+
+/// Flushes spans still waiting in the batch exporter.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
@@ -11,3 +11,73 @@ CREATE TABLE IF NOT EXISTS users (
 -- salt/password for plaintext password TopSecret0!
 INSERT INTO users (id, name, email, hashpassword, salt)
 VALUES (gen_random_uuid(), 'John Doe', 'john@example.com', 'X864zD50ii23b75iB8UBUrbf0HTIHGRkHuR+ioTD9WE=', 'qmyRlRwXH83LqAUz/V5AUA==');
+
+-- lets simple-auth evict cached users when their row changes (see user_cache in the servers)
+CREATE OR REPLACE FUNCTION notify_user_changed() RETURNS trigger AS $$
+BEGIN
+  IF TG_OP <> 'INSERT' THEN
+    PERFORM pg_notify('user_changed', OLD.email);
+  END IF;
+  IF TG_OP <> 'DELETE' THEN
+    PERFORM pg_notify('user_changed', NEW.email);
+  END IF;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS users_changed ON users;
+CREATE TRIGGER users_changed AFTER INSERT OR UPDATE OR DELETE ON users
+  FOR EACH ROW EXECUTE FUNCTION notify_user_changed();
+
+-- authentication audit trail written by simple-auth (audit.sink = postgres); rows can never change
+CREATE TABLE IF NOT EXISTS audit_events (
+  id BIGSERIAL PRIMARY KEY,
+  occurred_at TIMESTAMPTZ NOT NULL,
+  event VARCHAR(64) NOT NULL,
+  details JSONB NOT NULL
+);
+
+CREATE OR REPLACE FUNCTION reject_audit_change() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS audit_events_append_only ON audit_events;
+CREATE TRIGGER audit_events_append_only BEFORE UPDATE OR DELETE OR TRUNCATE ON audit_events
+  FOR EACH STATEMENT EXECUTE FUNCTION reject_audit_change();
+
+-- password changes are audited here, whatever changed the row; details match the events simple-auth writes
+CREATE OR REPLACE FUNCTION audit_password_changed() RETURNS trigger AS $$
+BEGIN
+  INSERT INTO audit_events (occurred_at, event, details)
+  VALUES (now(), 'password_changed',
+          jsonb_build_object('event', 'password_changed', 'user_id', NEW.id, 'email', NEW.email));
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS users_password_changed ON users;
+CREATE TRIGGER users_password_changed AFTER UPDATE OF hashpassword ON users
+  FOR EACH ROW WHEN (OLD.hashpassword IS DISTINCT FROM NEW.hashpassword)
+  EXECUTE FUNCTION audit_password_changed();
+
+-- extra user attributes that claims rules (claims.id_token / claims.access_token) can map into tokens;
+-- multi-valued attributes such as groups have one row per value
+CREATE TABLE IF NOT EXISTS user_attributes (
+  user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  name VARCHAR(255) NOT NULL,
+  value VARCHAR(1024) NOT NULL,
+  PRIMARY KEY (user_id, name, value)
+);
+
+-- opaque access tokens (clients[].access_token_format or auth.access_token_format = "opaque"), stored by
+-- SHA-256 hash and resolved via /introspect
+CREATE TABLE IF NOT EXISTS access_tokens (
+  token_hash CHAR(64) PRIMARY KEY,
+  user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  client_id VARCHAR(255),
+  claims JSONB NOT NULL,
+  expires_at TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX IF NOT EXISTS access_tokens_expires_at ON access_tokens (expires_at);